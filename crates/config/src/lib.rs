@@ -0,0 +1,1586 @@
+use leyline_error::GatewayError;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+pub mod reload;
+
+pub const DEFAULT_MAX_REPLAY_BODY_BYTES: usize = 1024 * 1024;
+// Weights are relative, so this leaves plenty of room while keeping balancer
+// tables, such as the ring of virtual nodes, small
+pub const MAX_UPSTREAM_WEIGHT: u32 = 1000;
+// Upper bound for every timeout, interval and delay, so deadlines computed
+// from them always fit in an `Instant`
+pub const MAX_DURATION_SECONDS: u64 = 30 * 24 * 60 * 60;
+
+/// Top level gateway configuration, loaded from a TOML or YAML file.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct GatewayConfig {
+    pub listeners: Vec<ListenerConfig>,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    // Admin endpoints are only served when this section is present
+    #[serde(default)]
+    pub admin: Option<AdminConfig>,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    // Defaults for services that don't set their own
+    #[serde(default)]
+    pub timeouts: TimeoutConfig,
+    pub services: Vec<ServiceConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    pub address: SocketAddr,
+}
+
+// Auth is off when the section is omitted, and on by default once it is present
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuthConfig {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub api_keys: Vec<String>,
+}
+
+/// A listener of its own for the admin endpoints, with keys separate from the
+/// client API keys.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminConfig {
+    pub address: SocketAddr,
+    pub api_keys: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct LoggingConfig {
+    #[serde(default = "default_log_directory")]
+    pub directory: PathBuf,
+    #[serde(default = "default_log_file_name")]
+    pub file_name: String,
+    // Used when RUST_LOG is not set
+    #[serde(default = "default_log_filter")]
+    pub filter: String,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            directory: default_log_directory(),
+            file_name: default_log_file_name(),
+            filter: default_log_filter(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServiceConfig {
+    // Path prefix, segments like `{id}` match any single segment
+    pub prefix: String,
+    // Further conditions a request has to meet to be routed here
+    #[serde(default, rename = "match")]
+    pub matcher: RouteMatchConfig,
+    // Receives requests no route matches, with the whole path
+    #[serde(default)]
+    pub default_route: bool,
+    // Left out when `split` lists the upstreams per cluster
+    #[serde(default)]
+    pub upstream_urls: Vec<UpstreamConfig>,
+    // Weighted traffic split across clusters, such as stable and canary
+    #[serde(default)]
+    pub split: Option<SplitConfig>,
+    // Methods the service accepts, any method is forwarded when unset
+    #[serde(default)]
+    pub allowed_methods: Option<Vec<String>>,
+    #[serde(default)]
+    pub load_balancing: LoadBalancingPolicy,
+    // Required by the ring_hash and maglev policies
+    #[serde(default)]
+    pub hash_key: Option<HashKey>,
+    #[serde(default)]
+    pub sticky_session: Option<StickySessionConfig>,
+    #[serde(default)]
+    pub health_check: Option<HealthCheckConfig>,
+    #[serde(default)]
+    pub outlier_detection: Option<OutlierDetectionConfig>,
+    #[serde(default)]
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    // Overall deadline for a request, covering every retry attempt
+    #[serde(default = "default_timeout_seconds")]
+    pub timeout_seconds: u64,
+    #[serde(default)]
+    pub timeouts: TimeoutConfig,
+    // Defaults to the number of upstream servers
+    #[serde(default)]
+    pub max_retries: Option<usize>,
+    // Request bodies up to this size are buffered so retries can resend them,
+    // larger ones are streamed to a single upstream and never retried
+    #[serde(default = "default_max_replay_body_bytes")]
+    pub max_replay_body_bytes: usize,
+    #[serde(default)]
+    pub retry: RetryPolicyConfig,
+    #[serde(default)]
+    pub retry_budget: Option<RetryBudgetConfig>,
+    #[serde(default)]
+    pub hedge: Option<HedgeConfig>,
+    #[serde(default)]
+    pub streaming: StreamingConfig,
+    // Proxy `Upgrade` handshakes such as WebSockets, off unless configured
+    #[serde(default)]
+    pub upgrade: Option<UpgradeConfig>,
+    // How the path and Host header are rewritten, by default the prefix is stripped
+    #[serde(default)]
+    pub rewrite: Option<RewriteConfig>,
+    // Return the last upstream 5xx response as is once retries are exhausted,
+    // otherwise the gateway answers with its own 502
+    #[serde(default = "default_true")]
+    pub forward_upstream_errors: bool,
+}
+
+/// Conditions on top of the path prefix. Empty lists match anything.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteMatchConfig {
+    // Host names, `*.example.com` matches any subdomain of example.com
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    #[serde(default)]
+    pub methods: Vec<String>,
+    // All of these have to match
+    #[serde(default)]
+    pub headers: Vec<FieldMatchConfig>,
+    #[serde(default)]
+    pub query: Vec<FieldMatchConfig>,
+    // Among services matching the same path, higher priorities are tried first
+    #[serde(default)]
+    pub priority: i32,
+}
+
+impl RouteMatchConfig {
+    /// Whether every request matching `other` matches this as well, as far as
+    /// can be told without evaluating wildcards and regexes.
+    fn covers(&self, other: &RouteMatchConfig) -> bool {
+        let covers_list = |mine: &[String], theirs: &[String]| {
+            mine.is_empty()
+                || (!theirs.is_empty() && theirs.iter().all(|t| mine.iter().any(|m| m.eq_ignore_ascii_case(t))))
+        };
+        covers_list(&self.hosts, &other.hosts)
+            && covers_list(&self.methods, &other.methods)
+            && self.headers.iter().all(|header| other.headers.contains(header))
+            && self.query.iter().all(|param| other.query.contains(param))
+    }
+}
+
+/// A header or query parameter condition: present, equal to `value`, or
+/// matching `regex`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FieldMatchConfig {
+    pub name: String,
+    #[serde(default)]
+    pub value: Option<String>,
+    #[serde(default)]
+    pub regex: Option<String>,
+}
+
+/// Traffic of a route divided across clusters of upstreams by weight.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SplitConfig {
+    pub clusters: Vec<ClusterConfig>,
+    // Requests with the same key go to the same cluster while the weights stay
+    // the same, requests without one are assigned at random
+    #[serde(default)]
+    pub user_key: Option<HashKey>,
+    // Header and cookie naming a cluster to send the request to, whatever the weights
+    #[serde(default)]
+    pub override_header: Option<String>,
+    #[serde(default)]
+    pub override_cookie: Option<String>,
+}
+
+/// A set of upstreams receiving a share of a route's traffic. Every other
+/// setting of the service applies to each cluster separately.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClusterConfig {
+    pub name: String,
+    // Relative to the other clusters, 0 only sends overridden requests here
+    pub weight: u32,
+    pub upstream_urls: Vec<UpstreamConfig>,
+}
+
+/// An upstream server, either a bare URL or a URL with a weight.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum UpstreamConfig {
+    Url(String),
+    Weighted {
+        url: String,
+        #[serde(default = "default_weight")]
+        weight: u32,
+    },
+}
+
+impl UpstreamConfig {
+    pub fn url(&self) -> &str {
+        match self {
+            UpstreamConfig::Url(url) => url,
+            UpstreamConfig::Weighted { url, .. } => url,
+        }
+    }
+
+    pub fn weight(&self) -> u32 {
+        match self {
+            UpstreamConfig::Url(_) => default_weight(),
+            UpstreamConfig::Weighted { weight, .. } => *weight,
+        }
+    }
+}
+
+impl From<&str> for UpstreamConfig {
+    fn from(url: &str) -> Self {
+        UpstreamConfig::Url(url.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LoadBalancingPolicy {
+    #[default]
+    RoundRobin,
+    // Smooth weighted round-robin, as in nginx
+    WeightedRoundRobin,
+    LeastRequest,
+    Random,
+    // Power of two random choices, compared by EWMA latency and in-flight requests
+    P2cEwma,
+    // Consistent hashing on `hash_key`
+    RingHash,
+    Maglev,
+}
+
+impl LoadBalancingPolicy {
+    pub fn is_consistent_hash(&self) -> bool {
+        matches!(self, LoadBalancingPolicy::RingHash | LoadBalancingPolicy::Maglev)
+    }
+}
+
+/// Which part of the request consistent-hash balancers hash on.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HashKey {
+    Header(String),
+    Cookie(String),
+    Query(String),
+    ClientIp,
+}
+
+/// Session affinity through a gateway-signed cookie naming the chosen upstream.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct StickySessionConfig {
+    #[serde(default = "default_sticky_cookie_name")]
+    pub cookie_name: String,
+    // HMAC key used to sign the cookie
+    pub secret: String,
+    // Session cookie when unset
+    #[serde(default)]
+    pub max_age_seconds: Option<u64>,
+}
+
+/// Background probing of every upstream of a service.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct HealthCheckConfig {
+    #[serde(default)]
+    pub kind: HealthCheckKind,
+    // Request path for HTTP checks
+    #[serde(default = "default_health_check_path")]
+    pub path: String,
+    #[serde(default = "default_health_check_interval")]
+    pub interval_seconds: u64,
+    #[serde(default = "default_health_check_timeout")]
+    pub timeout_seconds: u64,
+    // Consecutive successes before a down endpoint is marked up again
+    #[serde(default = "default_healthy_threshold")]
+    pub healthy_threshold: u32,
+    // Consecutive failures before an endpoint is marked down
+    #[serde(default = "default_unhealthy_threshold")]
+    pub unhealthy_threshold: u32,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthCheckKind {
+    // GET `path`, healthy on a 2xx or 3xx status
+    #[default]
+    Http,
+    // Plain TCP connect
+    Tcp,
+}
+
+/// Passive ejection of upstreams that fail real requests (timeouts, connect errors, 5xx).
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct OutlierDetectionConfig {
+    #[serde(default = "default_consecutive_failures")]
+    pub consecutive_failures: u32,
+    // Percentage of failed requests within `interval_seconds` that ejects an upstream
+    #[serde(default = "default_failure_rate_percent")]
+    pub failure_rate_percent: u32,
+    // Requests needed within the interval before the failure rate is considered
+    #[serde(default = "default_failure_rate_minimum_requests")]
+    pub failure_rate_minimum_requests: u32,
+    #[serde(default = "default_outlier_interval")]
+    pub interval_seconds: u64,
+    // Doubled for every consecutive ejection of the same upstream
+    #[serde(default = "default_base_ejection")]
+    pub base_ejection_seconds: u64,
+    #[serde(default = "default_max_ejection")]
+    pub max_ejection_seconds: u64,
+    #[serde(default = "default_max_ejection_percent")]
+    pub max_ejection_percent: u32,
+}
+
+/// When a failed attempt is retried on another upstream, and how long to wait first.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetryPolicyConfig {
+    #[serde(default = "default_retry_on")]
+    pub retry_on: Vec<RetryOn>,
+    // Upstream 5xx statuses that are retried
+    #[serde(default = "default_retry_status_codes")]
+    pub status_codes: Vec<u16>,
+    // Non-idempotent methods (POST, PATCH, ...) are otherwise only retried when
+    // the upstream could not be connected to
+    #[serde(default)]
+    pub retry_non_idempotent: bool,
+    // Exponential backoff with full jitter between attempts
+    #[serde(default = "default_backoff_base_ms")]
+    pub backoff_base_ms: u64,
+    #[serde(default = "default_backoff_max_ms")]
+    pub backoff_max_ms: u64,
+    // Wait at least as long as an upstream's Retry-After asks for
+    #[serde(default = "default_true")]
+    pub honor_retry_after: bool,
+}
+
+impl Default for RetryPolicyConfig {
+    fn default() -> Self {
+        Self {
+            retry_on: default_retry_on(),
+            status_codes: default_retry_status_codes(),
+            retry_non_idempotent: false,
+            backoff_base_ms: default_backoff_base_ms(),
+            backoff_max_ms: default_backoff_max_ms(),
+            honor_retry_after: true,
+        }
+    }
+}
+
+/// Caps retries to a share of recent traffic so a brownout doesn't multiply load.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetryBudgetConfig {
+    // Retries allowed as a percentage of requests within the window
+    #[serde(default = "default_budget_percent")]
+    pub budget_percent: u32,
+    // Retries always allowed regardless of traffic
+    #[serde(default = "default_min_retries_per_second")]
+    pub min_retries_per_second: u32,
+    #[serde(default = "default_budget_window")]
+    pub window_seconds: u64,
+}
+
+/// Hedging of safe requests: a slow attempt gets a parallel one to another upstream.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct HedgeConfig {
+    // Wait this long for response headers before hedging
+    #[serde(default = "default_hedge_delay_ms")]
+    pub delay_ms: u64,
+    // Hedge after this percentile of the service's recent latencies instead,
+    // `delay_ms` is used until enough requests were seen
+    #[serde(default)]
+    pub percentile: Option<u32>,
+}
+
+/// Long-lived streaming responses such as Server-Sent Events. These are exempt
+/// from the request deadline and only closed when they go quiet.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct StreamingConfig {
+    // Responses with one of these media types are streams
+    #[serde(default = "default_stream_content_types")]
+    pub content_types: Vec<String>,
+    // Also treat chunked responses of unknown length as streams, e.g. long polling
+    #[serde(default)]
+    pub chunked: bool,
+    // Longest pause between two chunks of a stream
+    #[serde(default = "default_stream_idle_timeout")]
+    pub idle_timeout_seconds: u64,
+}
+
+impl Default for StreamingConfig {
+    fn default() -> Self {
+        Self {
+            content_types: default_stream_content_types(),
+            chunked: false,
+            idle_timeout_seconds: default_stream_idle_timeout(),
+        }
+    }
+}
+
+/// Connections upgraded to another protocol, such as WebSockets.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpgradeConfig {
+    // Values of the `Upgrade` header that are let through, matched case-insensitively
+    #[serde(default = "default_upgrade_protocols")]
+    pub protocols: Vec<String>,
+    // Upgraded connections with no traffic either way for this long are closed
+    #[serde(default = "default_upgrade_idle_timeout")]
+    pub idle_timeout_seconds: u64,
+}
+
+/// Changes made to a request before it is forwarded. The path of the upstream
+/// URL, if any, is put in front of the rewritten path.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RewriteConfig {
+    // Replaces the route prefix instead of stripping it, `{param}` inserts a path parameter
+    #[serde(default)]
+    pub prefix: Option<String>,
+    // Replaces the first match in the path after the prefix rewrite, `$1` and
+    // `${name}` in the replacement insert capture groups
+    #[serde(default)]
+    pub regex: Option<String>,
+    #[serde(default)]
+    pub replacement: Option<String>,
+    // Sent as the Host header instead of the upstream URL's host
+    #[serde(default)]
+    pub host: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryOn {
+    // The connection could not be established, the request never reached the upstream
+    ConnectFailure,
+    // The connection broke after the request was sent
+    Reset,
+    // No response headers within the first byte timeout
+    Timeout,
+}
+
+/// Per-phase upstream timeouts, each bounded by the service's overall `timeout_seconds`.
+/// Unset phases are only limited by that deadline.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TimeoutConfig {
+    // Establishing the connection to an upstream
+    #[serde(default)]
+    pub connect_seconds: Option<u64>,
+    // From sending the request until the response headers arrive
+    #[serde(default)]
+    pub first_byte_seconds: Option<u64>,
+    // Longest pause between two chunks of the response body
+    #[serde(default)]
+    pub idle_body_seconds: Option<u64>,
+}
+
+impl TimeoutConfig {
+    /// Fills the phases this config leaves unset from `defaults`.
+    pub fn or(&self, defaults: &TimeoutConfig) -> TimeoutConfig {
+        TimeoutConfig {
+            connect_seconds: self.connect_seconds.or(defaults.connect_seconds),
+            first_byte_seconds: self.first_byte_seconds.or(defaults.first_byte_seconds),
+            idle_body_seconds: self.idle_body_seconds.or(defaults.idle_body_seconds),
+        }
+    }
+
+    fn validate(&self, field: &str) -> Result<(), GatewayError> {
+        let phases = [
+            ("connect_seconds", self.connect_seconds),
+            ("first_byte_seconds", self.first_byte_seconds),
+            ("idle_body_seconds", self.idle_body_seconds),
+        ];
+        for (name, seconds) in phases {
+            if let Some(seconds) = seconds {
+                validate_seconds(&format!("{}.{}", field, name), seconds)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Circuit breaking, applied to the service as a whole and to each of its upstreams.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CircuitBreakerConfig {
+    // Consecutive failures that open the circuit
+    #[serde(default = "default_circuit_failure_threshold")]
+    pub failure_threshold: u32,
+    // How long an open circuit fails fast before letting probes through
+    #[serde(default = "default_circuit_open_seconds")]
+    pub open_seconds: u64,
+    // Concurrent probe requests allowed while half-open
+    #[serde(default = "default_half_open_max_requests")]
+    pub half_open_max_requests: u32,
+    // Successful probes needed to close the circuit again
+    #[serde(default = "default_circuit_success_threshold")]
+    pub success_threshold: u32,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_log_directory() -> PathBuf {
+    PathBuf::from("./logs")
+}
+
+fn default_log_file_name() -> String {
+    "leyline.log".to_string()
+}
+
+fn default_log_filter() -> String {
+    "info,tower_http=debug".to_string()
+}
+
+fn default_sticky_cookie_name() -> String {
+    "leyline_affinity".to_string()
+}
+
+fn default_health_check_path() -> String {
+    "/health".to_string()
+}
+
+fn default_health_check_interval() -> u64 {
+    5
+}
+
+fn default_health_check_timeout() -> u64 {
+    2
+}
+
+fn default_healthy_threshold() -> u32 {
+    2
+}
+
+fn default_unhealthy_threshold() -> u32 {
+    3
+}
+
+fn default_consecutive_failures() -> u32 {
+    5
+}
+
+fn default_failure_rate_percent() -> u32 {
+    50
+}
+
+fn default_failure_rate_minimum_requests() -> u32 {
+    20
+}
+
+fn default_outlier_interval() -> u64 {
+    10
+}
+
+fn default_base_ejection() -> u64 {
+    30
+}
+
+fn default_max_ejection() -> u64 {
+    300
+}
+
+fn default_max_ejection_percent() -> u32 {
+    50
+}
+
+fn default_circuit_failure_threshold() -> u32 {
+    5
+}
+
+fn default_circuit_open_seconds() -> u64 {
+    30
+}
+
+fn default_half_open_max_requests() -> u32 {
+    1
+}
+
+fn default_circuit_success_threshold() -> u32 {
+    2
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+fn default_retry_on() -> Vec<RetryOn> {
+    vec![RetryOn::ConnectFailure, RetryOn::Reset, RetryOn::Timeout]
+}
+
+fn default_retry_status_codes() -> Vec<u16> {
+    vec![502, 503, 504]
+}
+
+fn default_backoff_base_ms() -> u64 {
+    25
+}
+
+fn default_backoff_max_ms() -> u64 {
+    250
+}
+
+fn default_budget_percent() -> u32 {
+    20
+}
+
+fn default_min_retries_per_second() -> u32 {
+    3
+}
+
+fn default_budget_window() -> u64 {
+    10
+}
+
+fn default_hedge_delay_ms() -> u64 {
+    100
+}
+
+fn default_stream_content_types() -> Vec<String> {
+    vec!["text/event-stream".to_string()]
+}
+
+fn default_stream_idle_timeout() -> u64 {
+    300
+}
+
+fn default_upgrade_protocols() -> Vec<String> {
+    vec!["websocket".to_string()]
+}
+
+fn default_upgrade_idle_timeout() -> u64 {
+    300
+}
+
+fn default_max_replay_body_bytes() -> usize {
+    DEFAULT_MAX_REPLAY_BODY_BYTES
+}
+
+fn default_timeout_seconds() -> u64 {
+    10
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Toml,
+    Yaml,
+}
+
+impl ConfigFormat {
+    /// Picks the format from the file extension, falling back to TOML.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("yaml") | Some("yml") => ConfigFormat::Yaml,
+            _ => ConfigFormat::Toml,
+        }
+    }
+}
+
+impl GatewayConfig {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, GatewayError> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path).map_err(|e| {
+            GatewayError::Config(format!("failed to read {}: {}", path.display(), e))
+        })?;
+
+        Self::from_str_with_format(&contents, ConfigFormat::from_path(path))
+            .map_err(|e| match e {
+                GatewayError::Config(msg) => GatewayError::Config(format!("{}: {}", path.display(), msg)),
+                other => other,
+            })
+    }
+
+    pub fn from_str_with_format(contents: &str, format: ConfigFormat) -> Result<Self, GatewayError> {
+        let config: GatewayConfig = match format {
+            ConfigFormat::Toml => toml::from_str(contents)
+                .map_err(|e| GatewayError::Config(format!("invalid TOML: {}", e)))?,
+            ConfigFormat::Yaml => serde_yaml::from_str(contents)
+                .map_err(|e| GatewayError::Config(format!("invalid YAML: {}", e)))?,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), GatewayError> {
+        if self.listeners.is_empty() {
+            return Err(invalid("listeners", "at least one listener is required"));
+        }
+
+        if self.auth.enabled && self.auth.api_keys.is_empty() {
+            return Err(invalid("auth.api_keys", "must not be empty when auth is enabled"));
+        }
+        if let Some(i) = self.auth.api_keys.iter().position(|key| key.trim().is_empty()) {
+            return Err(invalid(&format!("auth.api_keys[{}]", i), "must not be blank"));
+        }
+
+        if let Some(admin) = &self.admin {
+            if self.listeners.iter().any(|listener| listener.address == admin.address) {
+                return Err(invalid("admin.address", "must differ from every listener address"));
+            }
+            if admin.api_keys.is_empty() {
+                return Err(invalid("admin.api_keys", "must not be empty"));
+            }
+            if let Some(i) = admin.api_keys.iter().position(|key| key.trim().is_empty()) {
+                return Err(invalid(&format!("admin.api_keys[{}]", i), "must not be blank"));
+            }
+            if let Some(i) = admin.api_keys.iter().position(|key| self.auth.api_keys.contains(key)) {
+                return Err(invalid(&format!("admin.api_keys[{}]", i), "must not also be a client API key"));
+            }
+        }
+
+        if self.logging.file_name.is_empty() {
+            return Err(invalid("logging.file_name", "must not be empty"));
+        }
+        self.timeouts.validate("timeouts")?;
+
+        if self.services.is_empty() {
+            return Err(invalid("services", "at least one service is required"));
+        }
+        for (i, service) in self.services.iter().enumerate() {
+            let field = format!("services[{}]", i);
+            service.validate(&field)?;
+            self.check_shadowing(i)?;
+            if service.default_route
+                && let Some(other) = self.services[..i].iter().position(|other| other.default_route)
+            {
+                return Err(invalid(&format!("{}.default_route", field), &format!("is already set on services[{}]", other)));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl GatewayConfig {
+    /// Rejects services[i] when a service matching the same paths would always
+    /// be tried before it and take every request it could get.
+    fn check_shadowing(&self, i: usize) -> Result<(), GatewayError> {
+        let service = &self.services[i];
+        // Routes match whole path segments, so `/py` and `/py/` are the same path
+        let path = normalize_prefix(&service.prefix);
+        for (j, other) in self.services[..i].iter().enumerate() {
+            if normalize_prefix(&other.prefix) != path {
+                continue;
+            }
+            let (first, second) = match service.matcher.priority > other.matcher.priority {
+                true => ((i, service), (j, other)),
+                false => ((j, other), (i, service)),
+            };
+            if first.1.matcher == second.1.matcher {
+                return Err(invalid(
+                    &format!("services[{}].prefix", i),
+                    &format!("'{}' matches the same paths as services[{}].prefix '{}'", service.prefix, j, other.prefix),
+                ));
+            }
+            if first.1.matcher.covers(&second.1.matcher) {
+                return Err(invalid(
+                    &format!("services[{}]", second.0),
+                    &format!("is shadowed by services[{}], which matches the same requests and is tried first", first.0),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ServiceConfig {
+    /// Names the route in logs, services can share a prefix when their match conditions differ.
+    pub fn route_key(&self) -> String {
+        if self.matcher == RouteMatchConfig::default() {
+            return self.prefix.clone();
+        }
+        let matcher = serde_json::to_string(&self.matcher).unwrap_or_default();
+        format!("{} {}", self.prefix, matcher)
+    }
+
+    fn validate(&self, field: &str) -> Result<(), GatewayError> {
+        if !self.prefix.starts_with('/') {
+            return Err(invalid(&format!("{}.prefix", field), "must start with '/'"));
+        }
+        if self.prefix.contains(['?', '#']) {
+            return Err(invalid(&format!("{}.prefix", field), "must be a path without a query or fragment"));
+        }
+        let mut params = Vec::new();
+        for segment in self.prefix.split('/') {
+            if !segment.contains(['{', '}']) {
+                continue;
+            }
+            let name = segment.strip_prefix('{').and_then(|segment| segment.strip_suffix('}'));
+            match name {
+                Some(name) if is_identifier(name) && !params.contains(&name) => params.push(name),
+                _ => return Err(invalid(
+                    &format!("{}.prefix", field),
+                    &format!("segment '{}' must be a parameter like {{id}} with a unique name, or contain no braces", segment),
+                )),
+            }
+        }
+        self.matcher.validate(&format!("{}.match", field))?;
+        if let Some(rewrite) = &self.rewrite {
+            rewrite.validate(&format!("{}.rewrite", field), &params)?;
+        }
+
+        match &self.split {
+            Some(split) => {
+                if !self.upstream_urls.is_empty() {
+                    return Err(invalid(&format!("{}.upstream_urls", field), "must be left out when split is set"));
+                }
+                split.validate(&format!("{}.split", field))?;
+            }
+            None => validate_upstreams(&format!("{}.upstream_urls", field), &self.upstream_urls)?,
+        }
+
+        if let Some(methods) = &self.allowed_methods {
+            let methods_field = format!("{}.allowed_methods", field);
+            if methods.is_empty() {
+                return Err(invalid(&methods_field, "must not be empty"));
+            }
+            validate_methods(&methods_field, methods)?;
+        }
+
+        if self.load_balancing.is_consistent_hash() && self.hash_key.is_none() {
+            return Err(invalid(&format!("{}.hash_key", field), "is required by the ring_hash and maglev policies"));
+        }
+        if let Some(hash_key) = &self.hash_key {
+            validate_hash_key(&format!("{}.hash_key", field), hash_key)?;
+        }
+
+        if let Some(sticky) = &self.sticky_session {
+            let sticky_field = format!("{}.sticky_session", field);
+            if sticky.cookie_name.is_empty() || !sticky.cookie_name.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c)) {
+                return Err(invalid(&format!("{}.cookie_name", sticky_field), "must be a non-empty token of letters, digits, '-', '_' or '.'"));
+            }
+            if sticky.secret.len() < 16 {
+                return Err(invalid(&format!("{}.secret", sticky_field), "must be at least 16 characters"));
+            }
+        }
+
+        if let Some(health_check) = &self.health_check {
+            let health_field = format!("{}.health_check", field);
+            if !health_check.path.starts_with('/') {
+                return Err(invalid(&format!("{}.path", health_field), "must start with '/'"));
+            }
+            validate_seconds(&format!("{}.interval_seconds", health_field), health_check.interval_seconds)?;
+            validate_seconds(&format!("{}.timeout_seconds", health_field), health_check.timeout_seconds)?;
+            if health_check.healthy_threshold == 0 || health_check.unhealthy_threshold == 0 {
+                return Err(invalid(&health_field, "thresholds must be greater than 0"));
+            }
+        }
+
+        if let Some(outlier) = &self.outlier_detection {
+            let outlier_field = format!("{}.outlier_detection", field);
+            if outlier.consecutive_failures == 0 {
+                return Err(invalid(&format!("{}.consecutive_failures", outlier_field), "must be greater than 0"));
+            }
+            if outlier.failure_rate_percent == 0 || outlier.failure_rate_percent > 100 {
+                return Err(invalid(&format!("{}.failure_rate_percent", outlier_field), "must be between 1 and 100"));
+            }
+            if outlier.max_ejection_percent > 100 {
+                return Err(invalid(&format!("{}.max_ejection_percent", outlier_field), "must be at most 100"));
+            }
+            if outlier.interval_seconds == 0 || outlier.base_ejection_seconds == 0 {
+                return Err(invalid(&outlier_field, "interval_seconds and base_ejection_seconds must be greater than 0"));
+            }
+            if outlier.max_ejection_seconds < outlier.base_ejection_seconds {
+                return Err(invalid(&format!("{}.max_ejection_seconds", outlier_field), "must not be less than base_ejection_seconds"));
+            }
+            validate_seconds(&format!("{}.interval_seconds", outlier_field), outlier.interval_seconds)?;
+            validate_seconds(&format!("{}.max_ejection_seconds", outlier_field), outlier.max_ejection_seconds)?;
+        }
+
+        if let Some(circuit) = &self.circuit_breaker {
+            let circuit_field = format!("{}.circuit_breaker", field);
+            if circuit.failure_threshold == 0 || circuit.success_threshold == 0 {
+                return Err(invalid(&circuit_field, "failure_threshold and success_threshold must be greater than 0"));
+            }
+            validate_seconds(&format!("{}.open_seconds", circuit_field), circuit.open_seconds)?;
+            if circuit.half_open_max_requests == 0 {
+                return Err(invalid(&format!("{}.half_open_max_requests", circuit_field), "must be greater than 0"));
+            }
+        }
+
+        validate_seconds(&format!("{}.timeout_seconds", field), self.timeout_seconds)?;
+        self.timeouts.validate(&format!("{}.timeouts", field))?;
+
+        let retry_field = format!("{}.retry", field);
+        if let Some(i) = self.retry.status_codes.iter().position(|code| !(500..=599).contains(code)) {
+            return Err(invalid(&format!("{}.status_codes[{}]", retry_field, i), "must be a 5xx status"));
+        }
+        if self.retry.backoff_max_ms < self.retry.backoff_base_ms {
+            return Err(invalid(&format!("{}.backoff_max_ms", retry_field), "must not be less than backoff_base_ms"));
+        }
+        if self.retry.backoff_max_ms / 1000 > MAX_DURATION_SECONDS {
+            return Err(invalid(&format!("{}.backoff_max_ms", retry_field), &format!("must be at most {} seconds", MAX_DURATION_SECONDS)));
+        }
+        if let Some(budget) = &self.retry_budget {
+            validate_seconds(&format!("{}.retry_budget.window_seconds", field), budget.window_seconds)?;
+        }
+
+        if let Some(hedge) = &self.hedge {
+            if hedge.delay_ms == 0 {
+                return Err(invalid(&format!("{}.hedge.delay_ms", field), "must be greater than 0"));
+            }
+            if hedge.delay_ms / 1000 > MAX_DURATION_SECONDS {
+                return Err(invalid(&format!("{}.hedge.delay_ms", field), &format!("must be at most {} seconds", MAX_DURATION_SECONDS)));
+            }
+            if hedge.percentile.is_some_and(|percentile| !(1..=99).contains(&percentile)) {
+                return Err(invalid(&format!("{}.hedge.percentile", field), "must be between 1 and 99"));
+            }
+        }
+        validate_seconds(&format!("{}.streaming.idle_timeout_seconds", field), self.streaming.idle_timeout_seconds)?;
+        if let Some(upgrade) = &self.upgrade {
+            if upgrade.protocols.is_empty() {
+                return Err(invalid(&format!("{}.upgrade.protocols", field), "must not be empty"));
+            }
+            validate_seconds(&format!("{}.upgrade.idle_timeout_seconds", field), upgrade.idle_timeout_seconds)?;
+        }
+        if self.max_retries == Some(0) {
+            return Err(invalid(&format!("{}.max_retries", field), "must be greater than 0"));
+        }
+
+        Ok(())
+    }
+}
+
+impl RouteMatchConfig {
+    fn validate(&self, field: &str) -> Result<(), GatewayError> {
+        for (i, host) in self.hosts.iter().enumerate() {
+            let name = host.strip_prefix("*.").unwrap_or(host);
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || "-.".contains(c)) {
+                return Err(invalid(&format!("{}.hosts[{}]", field, i), "must be a host name, optionally starting with '*.'"));
+            }
+        }
+        validate_methods(&format!("{}.methods", field), &self.methods)?;
+        for (i, header) in self.headers.iter().enumerate() {
+            let header_field = format!("{}.headers[{}]", field, i);
+            if http::HeaderName::from_bytes(header.name.as_bytes()).is_err() {
+                return Err(invalid(&format!("{}.name", header_field), "must be a valid header name"));
+            }
+            header.validate(&header_field)?;
+        }
+        for (i, param) in self.query.iter().enumerate() {
+            let param_field = format!("{}.query[{}]", field, i);
+            if param.name.is_empty() {
+                return Err(invalid(&format!("{}.name", param_field), "must not be empty"));
+            }
+            param.validate(&param_field)?;
+        }
+        Ok(())
+    }
+}
+
+impl SplitConfig {
+    fn validate(&self, field: &str) -> Result<(), GatewayError> {
+        if self.clusters.is_empty() {
+            return Err(invalid(&format!("{}.clusters", field), "must not be empty"));
+        }
+        for (i, cluster) in self.clusters.iter().enumerate() {
+            let cluster_field = format!("{}.clusters[{}]", field, i);
+            if cluster.name.is_empty() {
+                return Err(invalid(&format!("{}.name", cluster_field), "must not be empty"));
+            }
+            if self.clusters[..i].iter().any(|other| other.name == cluster.name) {
+                return Err(invalid(&format!("{}.name", cluster_field), &format!("'{}' is used by another cluster", cluster.name)));
+            }
+            validate_upstreams(&format!("{}.upstream_urls", cluster_field), &cluster.upstream_urls)?;
+        }
+        if self.clusters.iter().all(|cluster| cluster.weight == 0) {
+            return Err(invalid(&format!("{}.clusters", field), "need a weight greater than 0 on at least one cluster"));
+        }
+        if let Some(user_key) = &self.user_key {
+            validate_hash_key(&format!("{}.user_key", field), user_key)?;
+        }
+        if let Some(header) = &self.override_header
+            && header.parse::<http::HeaderName>().is_err()
+        {
+            return Err(invalid(&format!("{}.override_header", field), &format!("'{}' is not a valid header name", header)));
+        }
+        if self.override_cookie.as_ref().is_some_and(|cookie| cookie.is_empty()) {
+            return Err(invalid(&format!("{}.override_cookie", field), "must not be empty"));
+        }
+        Ok(())
+    }
+}
+
+impl RewriteConfig {
+    /// `params` are the parameters of the route prefix, the only ones a rewrite can use.
+    fn validate(&self, field: &str, params: &[&str]) -> Result<(), GatewayError> {
+        if let Some(prefix) = &self.prefix {
+            let prefix_field = format!("{}.prefix", field);
+            if !prefix.starts_with('/') {
+                return Err(invalid(&prefix_field, "must start with '/'"));
+            }
+            if prefix.contains(['?', '#']) {
+                return Err(invalid(&prefix_field, "must be a path without a query or fragment"));
+            }
+            let mut rest = prefix.as_str();
+            while let Some(start) = rest.find(['{', '}']) {
+                let name = rest[start..].strip_prefix('{').and_then(|tail| Some(&tail[..tail.find('}')?]));
+                match name {
+                    Some(name) if params.contains(&name) => rest = &rest[start + name.len() + 2..],
+                    _ => return Err(invalid(&prefix_field, "can only use {param} for parameters of the service prefix")),
+                }
+            }
+        }
+        match (&self.regex, &self.replacement) {
+            (Some(regex), Some(_)) => {
+                regex::Regex::new(regex).map_err(|e| invalid(&format!("{}.regex", field), &format!("is invalid: {}", e)))?;
+            }
+            (None, None) => {}
+            _ => return Err(invalid(field, "needs both regex and replacement")),
+        }
+        if let Some(host) = &self.host
+            && (host.is_empty() || http::HeaderValue::from_str(host).is_err())
+        {
+            return Err(invalid(&format!("{}.host", field), "must be a valid Host header value"));
+        }
+        Ok(())
+    }
+}
+
+impl FieldMatchConfig {
+    fn validate(&self, field: &str) -> Result<(), GatewayError> {
+        match (&self.value, &self.regex) {
+            (Some(_), Some(_)) => Err(invalid(field, "can only have one of value and regex")),
+            (None, Some(regex)) => regex::Regex::new(regex)
+                .map(|_| ())
+                .map_err(|e| invalid(&format!("{}.regex", field), &format!("is invalid: {}", e))),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn validate_upstreams(field: &str, upstreams: &[UpstreamConfig]) -> Result<(), GatewayError> {
+    if upstreams.is_empty() {
+        return Err(invalid(field, "at least one upstream is required"));
+    }
+    for (i, upstream) in upstreams.iter().enumerate() {
+        let upstream_field = format!("{}[{}]", field, i);
+        validate_upstream_url(upstream.url()).map_err(|reason| invalid(&upstream_field, &reason))?;
+        if upstream.weight() == 0 {
+            return Err(invalid(&format!("{}.weight", upstream_field), "must be greater than 0"));
+        }
+        if upstream.weight() > MAX_UPSTREAM_WEIGHT {
+            return Err(invalid(&format!("{}.weight", upstream_field), &format!("must be at most {}", MAX_UPSTREAM_WEIGHT)));
+        }
+    }
+    Ok(())
+}
+
+fn validate_seconds(field: &str, seconds: u64) -> Result<(), GatewayError> {
+    if seconds == 0 {
+        return Err(invalid(field, "must be greater than 0"));
+    }
+    if seconds > MAX_DURATION_SECONDS {
+        return Err(invalid(field, &format!("must be at most {}", MAX_DURATION_SECONDS)));
+    }
+    Ok(())
+}
+
+fn validate_hash_key(field: &str, key: &HashKey) -> Result<(), GatewayError> {
+    match key {
+        HashKey::Header(name) if name.parse::<http::HeaderName>().is_err() => {
+            Err(invalid(&format!("{}.header", field), &format!("'{}' is not a valid header name", name)))
+        }
+        HashKey::Cookie(name) | HashKey::Query(name) if name.is_empty() => Err(invalid(field, "name must not be empty")),
+        _ => Ok(()),
+    }
+}
+
+fn validate_methods(field: &str, methods: &[String]) -> Result<(), GatewayError> {
+    for (i, method) in methods.iter().enumerate() {
+        if method.is_empty() || http::Method::from_bytes(method.as_bytes()).is_err() {
+            return Err(invalid(&format!("{}[{}]", field, i), "must be an HTTP method token"));
+        }
+    }
+    Ok(())
+}
+
+fn is_identifier(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// A route prefix as the path segments it matches, ignoring empty ones and
+/// the names of parameters.
+fn normalize_prefix(prefix: &str) -> String {
+    let segments: Vec<&str> = prefix
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| if segment.starts_with('{') { "{}" } else { segment })
+        .collect();
+    format!("/{}", segments.join("/"))
+}
+
+fn validate_upstream_url(url: &str) -> Result<(), String> {
+    let uri: http::Uri = url.parse().map_err(|e| format!("invalid URL '{}': {}", url, e))?;
+    match uri.scheme_str() {
+        Some("http") | Some("https") => {}
+        _ => return Err(format!("'{}' must use the http or https scheme", url)),
+    }
+    if uri.authority().is_none() {
+        return Err(format!("'{}' is missing a host", url));
+    }
+    // A path is kept as the base path of forwarded requests, a query would get lost
+    if uri.query().is_some() {
+        return Err(format!("'{}' must not have a query", url));
+    }
+    Ok(())
+}
+
+fn invalid(field: &str, reason: &str) -> GatewayError {
+    GatewayError::Config(format!("{} {}", field, reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOML: &str = r#"
+        [[listeners]]
+        address = "127.0.0.1:3000"
+
+        [auth]
+        api_keys = ["my-secret-api-key-12345"]
+
+        [[services]]
+        prefix = "/py"
+        upstream_urls = ["http://127.0.0.1:8082", "http://127.0.0.1:8081"]
+        timeout_seconds = 10
+        max_retries = 2
+    "#;
+
+    fn config_error(result: Result<GatewayConfig, GatewayError>) -> String {
+        match result {
+            Err(GatewayError::Config(msg)) => msg,
+            other => panic!("expected config error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_toml() {
+        let config = GatewayConfig::from_str_with_format(TOML, ConfigFormat::Toml).unwrap();
+        assert_eq!(config.listeners[0].address, "127.0.0.1:3000".parse().unwrap());
+        assert!(config.auth.enabled);
+        assert_eq!(config.logging, LoggingConfig::default());
+        assert_eq!(config.services[0].prefix, "/py");
+        assert_eq!(config.services[0].upstream_urls.len(), 2);
+        assert_eq!(config.services[0].max_retries, Some(2));
+        assert_eq!(config.services[0].max_replay_body_bytes, 1024 * 1024);
+        assert!(config.services[0].forward_upstream_errors);
+        assert_eq!(config.services[0].load_balancing, LoadBalancingPolicy::RoundRobin);
+    }
+
+    #[test]
+    fn test_parse_weighted_upstreams() {
+        let toml = r#"
+            [[listeners]]
+            address = "127.0.0.1:3000"
+
+            [[services]]
+            prefix = "/py"
+            load_balancing = "weighted_round_robin"
+            upstream_urls = [
+                "http://127.0.0.1:8081",
+                { url = "http://127.0.0.1:8082", weight = 3 },
+            ]
+        "#;
+        let config = GatewayConfig::from_str_with_format(toml, ConfigFormat::Toml).unwrap();
+        let service = &config.services[0];
+        assert_eq!(service.load_balancing, LoadBalancingPolicy::WeightedRoundRobin);
+        assert_eq!(service.upstream_urls[0].weight(), 1);
+        assert_eq!(service.upstream_urls[1].url(), "http://127.0.0.1:8082");
+        assert_eq!(service.upstream_urls[1].weight(), 3);
+
+        let zero_weight = toml.replace("weight = 3", "weight = 0");
+        let msg = config_error(GatewayConfig::from_str_with_format(&zero_weight, ConfigFormat::Toml));
+        assert_eq!(msg, "services[0].upstream_urls[1].weight must be greater than 0");
+
+        let huge_weight = toml.replace("weight = 3", "weight = 30000000");
+        let msg = config_error(GatewayConfig::from_str_with_format(&huge_weight, ConfigFormat::Toml));
+        assert_eq!(msg, "services[0].upstream_urls[1].weight must be at most 1000");
+    }
+
+    #[test]
+    fn test_parse_yaml() {
+        let yaml = r#"
+listeners:
+  - address: "127.0.0.1:4000"
+logging:
+  file_name: leyline-envoy.log
+services:
+  - prefix: /api
+    upstream_urls: ["http://127.0.0.1:8080"]
+"#;
+        let config = GatewayConfig::from_str_with_format(yaml, ConfigFormat::Yaml).unwrap();
+        assert!(!config.auth.enabled);
+        assert_eq!(config.logging.file_name, "leyline-envoy.log");
+        assert_eq!(config.services[0].timeout_seconds, 10);
+        assert_eq!(config.services[0].max_retries, None);
+    }
+
+    #[test]
+    fn test_parse_hash_key() {
+        let toml = r#"
+            [[listeners]]
+            address = "127.0.0.1:3000"
+
+            [[services]]
+            prefix = "/py"
+            load_balancing = "maglev"
+            hash_key = { header = "x-user-id" }
+            upstream_urls = ["http://127.0.0.1:8081"]
+        "#;
+        let config = GatewayConfig::from_str_with_format(toml, ConfigFormat::Toml).unwrap();
+        assert_eq!(config.services[0].hash_key, Some(HashKey::Header("x-user-id".to_string())));
+
+        let client_ip = toml.replace(r#"{ header = "x-user-id" }"#, r#""client_ip""#);
+        let config = GatewayConfig::from_str_with_format(&client_ip, ConfigFormat::Toml).unwrap();
+        assert_eq!(config.services[0].hash_key, Some(HashKey::ClientIp));
+
+        let missing = toml.replace(r#"hash_key = { header = "x-user-id" }"#, "");
+        let msg = config_error(GatewayConfig::from_str_with_format(&missing, ConfigFormat::Toml));
+        assert_eq!(msg, "services[0].hash_key is required by the ring_hash and maglev policies");
+    }
+
+    #[test]
+    fn test_parse_sticky_session() {
+        let toml = format!("{}\n[services.sticky_session]\nsecret = \"0123456789abcdef\"\n", TOML);
+        let config = GatewayConfig::from_str_with_format(&toml, ConfigFormat::Toml).unwrap();
+        let sticky = config.services[0].sticky_session.as_ref().unwrap();
+        assert_eq!(sticky.cookie_name, "leyline_affinity");
+        assert_eq!(sticky.max_age_seconds, None);
+
+        let short_secret = toml.replace("0123456789abcdef", "short");
+        let msg = config_error(GatewayConfig::from_str_with_format(&short_secret, ConfigFormat::Toml));
+        assert_eq!(msg, "services[0].sticky_session.secret must be at least 16 characters");
+    }
+
+    #[test]
+    fn test_parse_health_check() {
+        let toml = format!("{}\n[services.health_check]\nkind = \"tcp\"\ninterval_seconds = 1\n", TOML);
+        let config = GatewayConfig::from_str_with_format(&toml, ConfigFormat::Toml).unwrap();
+        let health_check = config.services[0].health_check.as_ref().unwrap();
+        assert_eq!(health_check.kind, HealthCheckKind::Tcp);
+        assert_eq!(health_check.path, "/health");
+        assert_eq!(health_check.interval_seconds, 1);
+        assert_eq!((health_check.healthy_threshold, health_check.unhealthy_threshold), (2, 3));
+
+        let bad_path = toml.replace("kind = \"tcp\"", "path = \"health\"");
+        let msg = config_error(GatewayConfig::from_str_with_format(&bad_path, ConfigFormat::Toml));
+        assert_eq!(msg, "services[0].health_check.path must start with '/'");
+    }
+
+    #[test]
+    fn test_parse_outlier_detection() {
+        let toml = format!("{}\n[services.outlier_detection]\nconsecutive_failures = 3\n", TOML);
+        let config = GatewayConfig::from_str_with_format(&toml, ConfigFormat::Toml).unwrap();
+        let outlier = config.services[0].outlier_detection.as_ref().unwrap();
+        assert_eq!(outlier.consecutive_failures, 3);
+        assert_eq!((outlier.base_ejection_seconds, outlier.max_ejection_seconds), (30, 300));
+
+        let bad_max = toml.replace("consecutive_failures = 3", "max_ejection_seconds = 10");
+        let msg = config_error(GatewayConfig::from_str_with_format(&bad_max, ConfigFormat::Toml));
+        assert_eq!(msg, "services[0].outlier_detection.max_ejection_seconds must not be less than base_ejection_seconds");
+    }
+
+    #[test]
+    fn test_parse_circuit_breaker() {
+        let toml = format!("{}\n[services.circuit_breaker]\nfailure_threshold = 3\n", TOML);
+        let config = GatewayConfig::from_str_with_format(&toml, ConfigFormat::Toml).unwrap();
+        let circuit = config.services[0].circuit_breaker.as_ref().unwrap();
+        assert_eq!(circuit.failure_threshold, 3);
+        assert_eq!((circuit.open_seconds, circuit.half_open_max_requests, circuit.success_threshold), (30, 1, 2));
+
+        let bad_probes = toml.replace("failure_threshold = 3", "half_open_max_requests = 0");
+        let msg = config_error(GatewayConfig::from_str_with_format(&bad_probes, ConfigFormat::Toml));
+        assert_eq!(msg, "services[0].circuit_breaker.half_open_max_requests must be greater than 0");
+    }
+
+    #[test]
+    fn test_parse_timeouts() {
+        let toml = format!(
+            "[timeouts]\nconnect_seconds = 2\nidle_body_seconds = 30\n{}\n[services.timeouts]\nconnect_seconds = 1\nfirst_byte_seconds = 5\n",
+            TOML
+        );
+        let config = GatewayConfig::from_str_with_format(&toml, ConfigFormat::Toml).unwrap();
+        let timeouts = config.services[0].timeouts.or(&config.timeouts);
+        assert_eq!(timeouts.connect_seconds, Some(1));
+        assert_eq!(timeouts.first_byte_seconds, Some(5));
+        assert_eq!(timeouts.idle_body_seconds, Some(30));
+
+        let bad_timeout = toml.replace("first_byte_seconds = 5", "first_byte_seconds = 0");
+        let msg = config_error(GatewayConfig::from_str_with_format(&bad_timeout, ConfigFormat::Toml));
+        assert_eq!(msg, "services[0].timeouts.first_byte_seconds must be greater than 0");
+
+        let huge_timeout = toml.replace("timeout_seconds = 10", "timeout_seconds = 9223372036854775807");
+        let msg = config_error(GatewayConfig::from_str_with_format(&huge_timeout, ConfigFormat::Toml));
+        assert_eq!(msg, "services[0].timeout_seconds must be at most 2592000");
+        let huge_idle = toml.replace("idle_body_seconds = 30", "idle_body_seconds = 2592001");
+        let msg = config_error(GatewayConfig::from_str_with_format(&huge_idle, ConfigFormat::Toml));
+        assert_eq!(msg, "timeouts.idle_body_seconds must be at most 2592000");
+        let huge_open = format!("{}\n[services.circuit_breaker]\nopen_seconds = 9223372036854775807\n", TOML);
+        let msg = config_error(GatewayConfig::from_str_with_format(&huge_open, ConfigFormat::Toml));
+        assert_eq!(msg, "services[0].circuit_breaker.open_seconds must be at most 2592000");
+    }
+
+    #[test]
+    fn test_parse_retry_policy() {
+        let config = GatewayConfig::from_str_with_format(TOML, ConfigFormat::Toml).unwrap();
+        assert_eq!(config.services[0].retry, RetryPolicyConfig::default());
+
+        let toml = format!("{}\n[services.retry]\nretry_on = [\"connect_failure\"]\nstatus_codes = [503]\n", TOML);
+        let config = GatewayConfig::from_str_with_format(&toml, ConfigFormat::Toml).unwrap();
+        let retry = &config.services[0].retry;
+        assert_eq!(retry.retry_on, vec![RetryOn::ConnectFailure]);
+        assert_eq!(retry.status_codes, vec![503]);
+        assert!(!retry.retry_non_idempotent);
+
+        let toml = format!("{}\n[services.retry_budget]\nbudget_percent = 10\n", toml);
+        let config = GatewayConfig::from_str_with_format(&toml, ConfigFormat::Toml).unwrap();
+        let budget = config.services[0].retry_budget.as_ref().unwrap();
+        assert_eq!((budget.budget_percent, budget.min_retries_per_second, budget.window_seconds), (10, 3, 10));
+
+        let bad_status = toml.replace("[503]", "[503, 429]");
+        let msg = config_error(GatewayConfig::from_str_with_format(&bad_status, ConfigFormat::Toml));
+        assert_eq!(msg, "services[0].retry.status_codes[1] must be a 5xx status");
+    }
+
+    #[test]
+    fn test_parse_hedge() {
+        let toml = format!("{}\n[services.hedge]\npercentile = 95\n", TOML);
+        let config = GatewayConfig::from_str_with_format(&toml, ConfigFormat::Toml).unwrap();
+        let hedge = config.services[0].hedge.as_ref().unwrap();
+        assert_eq!((hedge.delay_ms, hedge.percentile), (100, Some(95)));
+
+        let bad_percentile = toml.replace("95", "100");
+        let msg = config_error(GatewayConfig::from_str_with_format(&bad_percentile, ConfigFormat::Toml));
+        assert_eq!(msg, "services[0].hedge.percentile must be between 1 and 99");
+    }
+
+    #[test]
+    fn test_parse_allowed_methods() {
+        let toml = TOML.replace("prefix = \"/py\"", "prefix = \"/py\"\nallowed_methods = [\"GET\", \"PROPFIND\"]");
+        let config = GatewayConfig::from_str_with_format(&toml, ConfigFormat::Toml).unwrap();
+        assert_eq!(config.services[0].allowed_methods.as_deref(), Some(&["GET".to_string(), "PROPFIND".to_string()][..]));
+
+        let bad_method = toml.replace("PROPFIND", "NOT A METHOD");
+        let msg = config_error(GatewayConfig::from_str_with_format(&bad_method, ConfigFormat::Toml));
+        assert_eq!(msg, "services[0].allowed_methods[1] must be an HTTP method token");
+    }
+
+    #[test]
+    fn test_parse_route_match() {
+        let toml = format!(
+            "{}\n[services.match]\nhosts = [\"*.example.com\"]\nmethods = [\"GET\"]\n\
+             headers = [{{ name = \"x-version\", regex = \"^v2\" }}]\nquery = [{{ name = \"debug\" }}]\npriority = 5\n",
+            TOML.replace("prefix = \"/py\"", "prefix = \"/users/{id}/orders\"")
+        );
+        let config = GatewayConfig::from_str_with_format(&toml, ConfigFormat::Toml).unwrap();
+        let matcher = &config.services[0].matcher;
+        assert_eq!(matcher.hosts, ["*.example.com"]);
+        assert_eq!(matcher.headers[0].regex.as_deref(), Some("^v2"));
+        assert_eq!(matcher.query[0], FieldMatchConfig { name: "debug".to_string(), value: None, regex: None });
+        assert_eq!(matcher.priority, 5);
+
+        let bad_regex = toml.replace("^v2", "(v2");
+        let msg = config_error(GatewayConfig::from_str_with_format(&bad_regex, ConfigFormat::Toml));
+        assert!(msg.starts_with("services[0].match.headers[0].regex is invalid"), "{}", msg);
+
+        let bad_param = toml.replace("{id}", "{id");
+        let msg = config_error(GatewayConfig::from_str_with_format(&bad_param, ConfigFormat::Toml));
+        assert_eq!(msg, "services[0].prefix segment '{id' must be a parameter like {id} with a unique name, or contain no braces");
+    }
+
+    #[test]
+    fn test_shadowed_routes() {
+        let service = |prefix: &str, matcher: &str| {
+            format!("\n[[services]]\nprefix = \"{}\"\nupstream_urls = [\"http://127.0.0.1:8081\"]\n{}\n", prefix, matcher)
+        };
+        let config = |services: &[String]| GatewayConfig::from_str_with_format(&format!("{}{}", TOML, services.concat()), ConfigFormat::Toml);
+
+        // Different hosts, or a more specific route tried first, are fine
+        let hosts = [service("/api", "match = { hosts = [\"a.com\"] }"), service("/api", "match = { hosts = [\"b.com\"] }")];
+        assert!(config(&hosts).is_ok());
+        let specific_first = [service("/api", "match = { methods = [\"GET\"] }"), service("/api", "")];
+        assert!(config(&specific_first).is_ok());
+
+        // A catch-all tried first leaves nothing for the more specific route
+        let catch_all_first = [service("/api", ""), service("/api", "match = { methods = [\"GET\"] }")];
+        let msg = config_error(config(&catch_all_first));
+        assert_eq!(msg, "services[2] is shadowed by services[1], which matches the same requests and is tried first");
+        let prioritized = [service("/api", ""), service("/api", "match = { methods = [\"GET\"], priority = 1 }")];
+        assert!(config(&prioritized).is_ok());
+
+        // Parameter names don't make paths different
+        let params = [service("/users/{id}", ""), service("/users/{user}", "")];
+        let msg = config_error(config(&params));
+        assert_eq!(msg, "services[2].prefix '/users/{user}' matches the same paths as services[1].prefix '/users/{id}'");
+
+        let defaults = [service("/a", "default_route = true"), service("/b", "default_route = true")];
+        let msg = config_error(config(&defaults));
+        assert_eq!(msg, "services[2].default_route is already set on services[1]");
+    }
+
+    #[test]
+    fn test_parse_streaming() {
+        let config = GatewayConfig::from_str_with_format(TOML, ConfigFormat::Toml).unwrap();
+        assert_eq!(config.services[0].streaming, StreamingConfig::default());
+
+        let toml = format!("{}\n[services.streaming]\nchunked = true\nidle_timeout_seconds = 0\n", TOML);
+        let msg = config_error(GatewayConfig::from_str_with_format(&toml, ConfigFormat::Toml));
+        assert_eq!(msg, "services[0].streaming.idle_timeout_seconds must be greater than 0");
+    }
+
+    #[test]
+    fn test_parse_upgrade() {
+        let toml = format!("{}\n[services.upgrade]\nidle_timeout_seconds = 60\n", TOML);
+        let config = GatewayConfig::from_str_with_format(&toml, ConfigFormat::Toml).unwrap();
+        let upgrade = config.services[0].upgrade.as_ref().unwrap();
+        assert_eq!(upgrade.protocols, ["websocket"]);
+        assert_eq!(upgrade.idle_timeout_seconds, 60);
+
+        let no_protocols = format!("{}\n[services.upgrade]\nprotocols = []\n", TOML);
+        let msg = config_error(GatewayConfig::from_str_with_format(&no_protocols, ConfigFormat::Toml));
+        assert_eq!(msg, "services[0].upgrade.protocols must not be empty");
+    }
+
+    #[test]
+    fn test_parse_rewrite() {
+        let toml = format!(
+            "{}\n[services.rewrite]\nprefix = \"/v2/accounts/{{id}}\"\nregex = \"^(.*)/items$\"\nreplacement = \"$1/lines\"\nhost = \"internal\"\n",
+            TOML.replace("prefix = \"/py\"", "prefix = \"/users/{id}\"")
+        );
+        let config = GatewayConfig::from_str_with_format(&toml, ConfigFormat::Toml).unwrap();
+        let rewrite = config.services[0].rewrite.as_ref().unwrap();
+        assert_eq!(rewrite.prefix.as_deref(), Some("/v2/accounts/{id}"));
+        assert_eq!(rewrite.replacement.as_deref(), Some("$1/lines"));
+        assert_eq!(rewrite.host.as_deref(), Some("internal"));
+
+        let unknown_param = toml.replace("/v2/accounts/{id}", "/v2/{user}");
+        let msg = config_error(GatewayConfig::from_str_with_format(&unknown_param, ConfigFormat::Toml));
+        assert_eq!(msg, "services[0].rewrite.prefix can only use {param} for parameters of the service prefix");
+
+        let no_replacement = toml.replace("replacement = \"$1/lines\"\n", "");
+        let msg = config_error(GatewayConfig::from_str_with_format(&no_replacement, ConfigFormat::Toml));
+        assert_eq!(msg, "services[0].rewrite needs both regex and replacement");
+    }
+
+    #[test]
+    fn test_parse_split() {
+        let toml = r#"
+            [[listeners]]
+            address = "127.0.0.1:3000"
+
+            [auth]
+            enabled = false
+
+            [[services]]
+            prefix = "/api"
+            [services.split]
+            user_key = { cookie = "user_id" }
+            override_header = "x-cluster"
+            [[services.split.clusters]]
+            name = "stable"
+            weight = 95
+            upstream_urls = ["http://127.0.0.1:8081"]
+            [[services.split.clusters]]
+            name = "canary"
+            weight = 5
+            upstream_urls = ["http://127.0.0.1:9081"]
+        "#;
+        let config = GatewayConfig::from_str_with_format(toml, ConfigFormat::Toml).unwrap();
+        let split = config.services[0].split.as_ref().unwrap();
+        assert_eq!(split.clusters[1].name, "canary");
+        assert_eq!(split.clusters[1].weight, 5);
+        assert_eq!(split.user_key, Some(HashKey::Cookie("user_id".to_string())));
+        assert_eq!(split.override_cookie, None);
+
+        let both = toml.replace("prefix = \"/api\"", "prefix = \"/api\"\nupstream_urls = [\"http://127.0.0.1:8081\"]");
+        let msg = config_error(GatewayConfig::from_str_with_format(&both, ConfigFormat::Toml));
+        assert_eq!(msg, "services[0].upstream_urls must be left out when split is set");
+
+        let duplicate = toml.replace("\"canary\"", "\"stable\"");
+        let msg = config_error(GatewayConfig::from_str_with_format(&duplicate, ConfigFormat::Toml));
+        assert_eq!(msg, "services[0].split.clusters[1].name 'stable' is used by another cluster");
+
+        let no_weight = toml.replace("weight = 95", "weight = 0").replace("weight = 5", "weight = 0");
+        let msg = config_error(GatewayConfig::from_str_with_format(&no_weight, ConfigFormat::Toml));
+        assert_eq!(msg, "services[0].split.clusters need a weight greater than 0 on at least one cluster");
+    }
+
+    #[test]
+    fn test_format_from_path() {
+        assert_eq!(ConfigFormat::from_path(Path::new("gateway.yml")), ConfigFormat::Yaml);
+        assert_eq!(ConfigFormat::from_path(Path::new("gateway.yaml")), ConfigFormat::Yaml);
+        assert_eq!(ConfigFormat::from_path(Path::new("gateway.toml")), ConfigFormat::Toml);
+    }
+
+    #[test]
+    fn test_validation_errors() {
+        let bad_prefix = TOML.replace("prefix = \"/py\"", "prefix = \"py\"");
+        let msg = config_error(GatewayConfig::from_str_with_format(&bad_prefix, ConfigFormat::Toml));
+        assert_eq!(msg, "services[0].prefix must start with '/'");
+
+        let bad_url = TOML.replace("http://127.0.0.1:8081", "ftp://127.0.0.1:8081");
+        let msg = config_error(GatewayConfig::from_str_with_format(&bad_url, ConfigFormat::Toml));
+        assert!(msg.starts_with("services[0].upstream_urls[1]"), "{}", msg);
+
+        let url_query = TOML.replace("http://127.0.0.1:8081", "http://127.0.0.1:8081/base?x=1");
+        let msg = config_error(GatewayConfig::from_str_with_format(&url_query, ConfigFormat::Toml));
+        assert_eq!(msg, "services[0].upstream_urls[1] 'http://127.0.0.1:8081/base?x=1' must not have a query");
+
+        let no_keys = TOML.replace("api_keys = [\"my-secret-api-key-12345\"]", "");
+        let msg = config_error(GatewayConfig::from_str_with_format(&no_keys, ConfigFormat::Toml));
+        assert_eq!(msg, "auth.api_keys must not be empty when auth is enabled");
+
+        let admin = |section: &str| {
+            let toml = format!("{}\n[admin]\n{}\n", TOML, section);
+            GatewayConfig::from_str_with_format(&toml, ConfigFormat::Toml)
+        };
+        let config = admin("address = \"127.0.0.1:3001\"\napi_keys = [\"admin-key\"]").unwrap();
+        assert_eq!(config.admin.unwrap().address, "127.0.0.1:3001".parse().unwrap());
+        let msg = config_error(admin("address = \"127.0.0.1:3000\"\napi_keys = [\"admin-key\"]"));
+        assert_eq!(msg, "admin.address must differ from every listener address");
+        let msg = config_error(admin("address = \"127.0.0.1:3001\"\napi_keys = []"));
+        assert_eq!(msg, "admin.api_keys must not be empty");
+        let msg = config_error(admin("address = \"127.0.0.1:3001\"\napi_keys = [\"my-secret-api-key-12345\"]"));
+        assert_eq!(msg, "admin.api_keys[0] must not also be a client API key");
+
+        let duplicate = format!("{}\n[[services]]\nprefix = \"/py\"\nupstream_urls = [\"http://127.0.0.1:8081\"]\n", TOML);
+        let msg = config_error(GatewayConfig::from_str_with_format(&duplicate, ConfigFormat::Toml));
+        assert_eq!(msg, "services[1].prefix '/py' matches the same paths as services[0].prefix '/py'");
+        let ambiguous = duplicate.replace("prefix = \"/py\"\nupstream_urls = [\"http://127.0.0.1:8081\"]", "prefix = \"/py/\"\nupstream_urls = [\"http://127.0.0.1:8081\"]");
+        let msg = config_error(GatewayConfig::from_str_with_format(&ambiguous, ConfigFormat::Toml));
+        assert_eq!(msg, "services[1].prefix '/py/' matches the same paths as services[0].prefix '/py'");
+
+        let query_prefix = TOML.replace("prefix = \"/py\"", "prefix = \"/py?v=1\"");
+        let msg = config_error(GatewayConfig::from_str_with_format(&query_prefix, ConfigFormat::Toml));
+        assert_eq!(msg, "services[0].prefix must be a path without a query or fragment");
+
+        let unknown_field = TOML.replace("timeout_seconds", "timeout_secs");
+        let msg = config_error(GatewayConfig::from_str_with_format(&unknown_field, ConfigFormat::Toml));
+        assert!(msg.starts_with("invalid TOML"), "{}", msg);
+    }
+}
@@ -0,0 +1,306 @@
+use crate::{GatewayConfig, ServiceConfig};
+use leyline_error::GatewayError;
+use notify::{RecursiveMode, Watcher};
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+// Editors tend to emit several events per save, wait for them to settle
+const DEBOUNCE: Duration = Duration::from_millis(250);
+
+/// What changed between two configurations, keyed by `ServiceConfig::route_key`.
+#[derive(Debug, Default, PartialEq)]
+pub struct ConfigDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<(String, Vec<String>)>,
+    // Routes kept in both that are listed in a different order, which breaks ties between them
+    pub reordered: bool,
+    // Gateway-wide defaults that apply to every service
+    pub defaults: Vec<String>,
+    // Sections that are only read at startup
+    pub restart_required: Vec<&'static str>,
+}
+
+impl ConfigDiff {
+    pub fn between(old: &GatewayConfig, new: &GatewayConfig) -> Self {
+        let mut diff = ConfigDiff::default();
+
+        for service in &new.services {
+            let key = service.route_key();
+            match old.services.iter().find(|s| s.route_key() == key) {
+                None => diff.added.push(key),
+                Some(previous) if previous != service => {
+                    diff.changed.push((key, changed_fields(previous, service)));
+                }
+                Some(_) => {}
+            }
+        }
+        for service in &old.services {
+            let key = service.route_key();
+            if !new.services.iter().any(|s| s.route_key() == key) {
+                diff.removed.push(key);
+            }
+        }
+
+        let kept = |from: &GatewayConfig, to: &GatewayConfig| -> Vec<String> {
+            from.services
+                .iter()
+                .map(ServiceConfig::route_key)
+                .filter(|key| to.services.iter().any(|s| s.route_key() == *key))
+                .collect()
+        };
+        diff.reordered = kept(old, new) != kept(new, old);
+
+        if old.timeouts != new.timeouts {
+            diff.defaults.push(format!("timeouts: {} -> {}", json(&old.timeouts), json(&new.timeouts)));
+        }
+
+        if old.listeners != new.listeners {
+            diff.restart_required.push("listeners");
+        }
+        if old.auth != new.auth {
+            diff.restart_required.push("auth");
+        }
+        if old.admin != new.admin {
+            diff.restart_required.push("admin");
+        }
+        if old.logging != new.logging {
+            diff.restart_required.push("logging");
+        }
+
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.changed.is_empty()
+            && !self.reordered
+            && self.defaults.is_empty()
+            && self.restart_required.is_empty()
+    }
+
+    /// Whether anything changed that a reload can apply.
+    pub fn needs_apply(&self) -> bool {
+        !(self.added.is_empty()
+            && self.removed.is_empty()
+            && self.changed.is_empty()
+            && !self.reordered
+            && self.defaults.is_empty())
+    }
+
+    pub fn log(&self) {
+        if self.is_empty() {
+            tracing::info!("configuration reloaded, no changes");
+            return;
+        }
+        for prefix in &self.added {
+            tracing::info!("route added: {}", prefix);
+        }
+        for prefix in &self.removed {
+            tracing::info!("route removed: {}", prefix);
+        }
+        for (prefix, fields) in &self.changed {
+            for field in fields {
+                tracing::info!("route changed: {} {}", prefix, field);
+            }
+        }
+        if self.reordered {
+            tracing::info!("routes reordered");
+        }
+        for default in &self.defaults {
+            tracing::info!("default changed: {}", default);
+        }
+        for section in &self.restart_required {
+            tracing::warn!("changes to [{}] require a restart and were not applied", section);
+        }
+    }
+}
+
+fn json<T: serde::Serialize>(value: &T) -> Value {
+    serde_json::to_value(value).unwrap_or(Value::Null)
+}
+
+fn changed_fields(old: &ServiceConfig, new: &ServiceConfig) -> Vec<String> {
+    let (Ok(Value::Object(old)), Ok(Value::Object(new))) =
+        (serde_json::to_value(old), serde_json::to_value(new))
+    else {
+        return vec!["settings changed".to_string()];
+    };
+
+    new.iter()
+        .filter_map(|(key, value)| {
+            let previous = old.get(key).unwrap_or(&Value::Null);
+            (previous != value).then(|| format!("{}: {} -> {}", key, previous, value))
+        })
+        .collect()
+}
+
+/// Reloads the configuration file on SIGHUP and whenever it changes on disk.
+///
+/// `apply` gets the running configuration and the new one, and only sees
+/// configurations that parsed and validated; if parsing or `apply` fails the
+/// error is logged and the previous routing table stays in place.
+pub fn spawn<F>(path: PathBuf, current: GatewayConfig, apply: F)
+where
+    F: Fn(&GatewayConfig, &GatewayConfig) -> Result<(), GatewayError> + Send + 'static,
+{
+    let (tx, mut rx) = mpsc::unbounded_channel::<&'static str>();
+
+    let watcher = match watch_file(&path, tx.clone()) {
+        Ok(watcher) => Some(watcher),
+        Err(e) => {
+            tracing::warn!("failed to watch {}, only SIGHUP will reload: {}", path.display(), e);
+            None
+        }
+    };
+
+    #[cfg(unix)]
+    tokio::spawn(async move {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                tracing::warn!("failed to install SIGHUP handler: {}", e);
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            if tx.send("SIGHUP").is_err() {
+                break;
+            }
+        }
+    });
+
+    tokio::spawn(async move {
+        // Keep the watcher alive for as long as we are reloading
+        let _watcher = watcher;
+        let mut current = current;
+
+        while let Some(trigger) = rx.recv().await {
+            tokio::time::sleep(DEBOUNCE).await;
+            while rx.try_recv().is_ok() {}
+
+            tracing::info!("reloading configuration from {} ({})", path.display(), trigger);
+            let result = GatewayConfig::from_file(&path).and_then(|new| {
+                let diff = ConfigDiff::between(&current, &new);
+                // Restart-only changes leave the routing table as it is
+                if diff.needs_apply() {
+                    apply(&current, &new)?;
+                }
+                Ok((new, diff))
+            });
+
+            match result {
+                Ok((new, diff)) => {
+                    diff.log();
+                    // Restart-only sections keep their running values, so later
+                    // reloads still report them
+                    current.services = new.services;
+                    current.timeouts = new.timeouts;
+                }
+                Err(e) => {
+                    tracing::error!("configuration reload failed, keeping previous routing table: {}", e);
+                }
+            }
+        }
+    });
+}
+
+fn watch_file(path: &Path, tx: mpsc::UnboundedSender<&'static str>) -> notify::Result<notify::RecommendedWatcher> {
+    // Watch the parent directory, editors often replace the file instead of writing to it
+    let file_name = path.file_name().map(|name| name.to_os_string());
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        let Ok(event) = event else { return };
+        if event.kind.is_access() {
+            return;
+        }
+        if event.paths.iter().any(|p| p.file_name() == file_name.as_deref()) {
+            let _ = tx.send("file change");
+        }
+    })?;
+    watcher.watch(&directory, RecursiveMode::NonRecursive)?;
+    Ok(watcher)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ConfigFormat;
+
+    fn parse(services: &str) -> GatewayConfig {
+        let toml = format!("[[listeners]]\naddress = \"127.0.0.1:3000\"\n{}", services);
+        GatewayConfig::from_str_with_format(&toml, ConfigFormat::Toml).unwrap()
+    }
+
+    #[test]
+    fn test_diff_services() {
+        let old = parse(r#"
+            [[services]]
+            prefix = "/py"
+            upstream_urls = ["http://127.0.0.1:8081"]
+
+            [[services]]
+            prefix = "/go"
+            upstream_urls = ["http://127.0.0.1:8082"]
+        "#);
+        let new = parse(r#"
+            [[services]]
+            prefix = "/py"
+            upstream_urls = ["http://127.0.0.1:8081", "http://127.0.0.1:8083"]
+
+            [[services]]
+            prefix = "/node"
+            upstream_urls = ["http://127.0.0.1:3001"]
+        "#);
+
+        let diff = ConfigDiff::between(&old, &new);
+        assert_eq!(diff.added, vec!["/node"]);
+        assert_eq!(diff.removed, vec!["/go"]);
+        assert_eq!(diff.changed.len(), 1);
+        assert_eq!(diff.changed[0].0, "/py");
+        assert_eq!(
+            diff.changed[0].1,
+            vec![r#"upstream_urls: ["http://127.0.0.1:8081"] -> ["http://127.0.0.1:8081","http://127.0.0.1:8083"]"#]
+        );
+        assert!(diff.restart_required.is_empty());
+        assert!(diff.needs_apply());
+        assert!(ConfigDiff::between(&new, &new).is_empty());
+    }
+
+    #[test]
+    fn test_diff_restart_only() {
+        let services = "[[services]]\nprefix = \"/py\"\nupstream_urls = [\"http://127.0.0.1:8081\"]\n";
+        let old = parse(services);
+        let new = parse(&format!("[logging]\nfilter = \"debug\"\n[auth]\napi_keys = [\"key\"]\n{}", services));
+
+        let diff = ConfigDiff::between(&old, &new);
+        assert_eq!(diff.restart_required, vec!["auth", "logging"]);
+        assert!(!diff.is_empty());
+        assert!(!diff.needs_apply());
+    }
+
+    #[test]
+    fn test_diff_reordered() {
+        let py = "[[services]]\nprefix = \"/py\"\nupstream_urls = [\"http://127.0.0.1:8081\"]\n";
+        let go = "[[services]]\nprefix = \"/go\"\nupstream_urls = [\"http://127.0.0.1:8082\"]\n";
+        let node = "[[services]]\nprefix = \"/node\"\nupstream_urls = [\"http://127.0.0.1:3001\"]\n";
+        let old = parse(&format!("{}{}", py, go));
+
+        let diff = ConfigDiff::between(&old, &parse(&format!("{}{}", go, py)));
+        assert!(diff.reordered);
+        assert!(diff.needs_apply());
+
+        // Adding or removing routes keeps the order of the others
+        assert!(!ConfigDiff::between(&old, &parse(&format!("{}{}{}", node, py, go))).reordered);
+        assert!(!ConfigDiff::between(&old, &parse(go)).reordered);
+    }
+}
@@ -0,0 +1,118 @@
+use crate::metrics::Registry;
+use crate::routing::RouteTable;
+use crate::split::TrafficSplit;
+use arc_swap::ArcSwap;
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// State of the admin listener, which is separate from the proxy listeners and
+/// only accepts the admin keys.
+#[derive(Clone)]
+pub struct AdminState {
+    pub routes: Arc<ArcSwap<RouteTable>>,
+    pub api_keys: Arc<Vec<String>>,
+}
+
+/// New weights for the traffic splits of the routes with `prefix`. Clusters
+/// left out keep their weight.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WeightUpdate {
+    pub prefix: String,
+    pub weights: BTreeMap<String, u32>,
+}
+
+/// Builds the router of the admin listener, every endpoint needs an admin key.
+pub fn router(state: AdminState) -> Router {
+    Router::new()
+        .route("/metrics", get(metrics))
+        .route("/admin/splits", get(list_splits).put(set_split_weights))
+        .layer(middleware::from_fn_with_state(state.clone(), require_admin_key))
+        .with_state(state)
+}
+
+async fn require_admin_key(State(state): State<AdminState>, request: Request, next: Next) -> Response {
+    let Some(admin_key) = request.headers().get("x-admin-key") else {
+        return error(StatusCode::UNAUTHORIZED, "Unauthorized", "Admin key required");
+    };
+    let admin_key = admin_key.to_str().unwrap_or("");
+    if !state.api_keys.iter().any(|key| key == admin_key) {
+        return error(StatusCode::UNAUTHORIZED, "Unauthorized", "Invalid admin key");
+    }
+    next.run(request).await
+}
+
+/// Counters and gauges in the Prometheus text format.
+pub async fn metrics() -> Response {
+    (
+        [(axum::http::header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        Registry::global().render(),
+    )
+        .into_response()
+}
+
+/// Lists the clusters and current weights of every traffic split.
+pub async fn list_splits(State(state): State<AdminState>) -> Response {
+    let routes = state.routes.load();
+    let splits: Vec<Value> = routes
+        .services()
+        .iter()
+        .filter_map(|service| Some(split_json(&service.prefix, service.split.as_deref()?)))
+        .collect();
+    Json(json!({ "splits": splits })).into_response()
+}
+
+/// Changes cluster weights until a reload changes the route. Either every
+/// split with the prefix accepts the new weights or none is changed.
+pub async fn set_split_weights(
+    State(state): State<AdminState>,
+    Json(update): Json<WeightUpdate>,
+) -> Response {
+    let routes = state.routes.load();
+    let splits: Vec<&TrafficSplit> = routes
+        .services()
+        .iter()
+        .filter(|service| service.prefix == update.prefix)
+        .filter_map(|service| service.split.as_deref())
+        .collect();
+    if splits.is_empty() {
+        let message = format!("No traffic split for {}", update.prefix);
+        return error(StatusCode::NOT_FOUND, "Not Found", &message);
+    }
+
+    let weights: Vec<(String, u32)> = update.weights.into_iter().collect();
+    if let Some(message) = splits.iter().find_map(|split| split.check_weights(&weights).err()) {
+        return error(StatusCode::BAD_REQUEST, "Bad Request", &message);
+    }
+    for split in &splits {
+        // Checked above, and only a reload replaces the splits
+        let _ = split.set_weights(&weights);
+    }
+    tracing::info!("traffic split weights of {} changed to {:?}", update.prefix, weights);
+    let splits: Vec<Value> = splits.iter().map(|split| split_json(&update.prefix, split)).collect();
+    Json(json!({ "splits": splits })).into_response()
+}
+
+fn split_json(prefix: &str, split: &TrafficSplit) -> Value {
+    let clusters: Vec<Value> = split
+        .clusters()
+        .iter()
+        .map(|cluster| json!({ "name": cluster.name, "weight": cluster.weight() }))
+        .collect();
+    json!({ "prefix": prefix, "clusters": clusters })
+}
+
+/// An error in the same shape as the ones the proxy answers with.
+fn error(status: StatusCode, error: &str, message: &str) -> Response {
+    (status, Json(json!({ "error": error, "message": message }))).into_response()
+}
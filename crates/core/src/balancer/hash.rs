@@ -0,0 +1,48 @@
+use axum::http::{header, HeaderMap, Uri};
+use leyline_config::HashKey;
+use std::net::IpAddr;
+
+/// Stable 64-bit hash (FNV-1a with a splitmix64 finalizer), so every gateway
+/// instance maps the same key to the same upstream.
+pub fn hash_bytes(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    hash ^= hash >> 30;
+    hash = hash.wrapping_mul(0xbf58476d1ce4e5b9);
+    hash ^= hash >> 27;
+    hash = hash.wrapping_mul(0x94d049bb133111eb);
+    hash ^ (hash >> 31)
+}
+
+/// Hashes the configured part of a request, `None` when the request doesn't carry it.
+pub fn request_hash(key: &HashKey, headers: &HeaderMap, uri: &Uri, client_ip: Option<IpAddr>) -> Option<u64> {
+    match key {
+        HashKey::Header(name) => headers.get(name.as_str()).map(|value| hash_bytes(value.as_bytes())),
+        HashKey::Cookie(name) => cookie_value(headers, name).map(|value| hash_bytes(value.as_bytes())),
+        HashKey::Query(name) => query_value(uri, name).map(|value| hash_bytes(value.as_bytes())),
+        HashKey::ClientIp => client_ip.map(|ip| hash_bytes(ip.to_string().as_bytes())),
+    }
+}
+
+pub(crate) fn cookie_value<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+fn query_value<'a>(uri: &'a Uri, name: &str) -> Option<&'a str> {
+    uri.query()?
+        .split('&')
+        .filter_map(|pair| pair.split_once('=').or(Some((pair, ""))))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
@@ -0,0 +1,36 @@
+use super::{LoadBalancer, PickContext};
+use crate::endpoint::Endpoint;
+use rand::Rng;
+use std::sync::Arc;
+
+/// Picks the endpoint with the fewest outstanding requests, breaking ties at random.
+#[derive(Debug, Default)]
+pub struct LeastRequest;
+
+impl LoadBalancer for LeastRequest {
+    fn pick(&self, endpoints: &[Arc<Endpoint>], ctx: &PickContext<'_>) -> Option<usize> {
+        let mut rng = rand::rng();
+        let mut best: Option<(usize, usize)> = None;
+        let mut ties = 0;
+
+        for index in ctx.eligible(endpoints) {
+            let in_flight = endpoints[index].in_flight();
+            match best {
+                Some((_, least)) if in_flight > least => {}
+                Some((_, least)) if in_flight == least => {
+                    // Reservoir sampling keeps each tied endpoint equally likely
+                    ties += 1;
+                    if rng.random_range(0..ties) == 0 {
+                        best = Some((index, in_flight));
+                    }
+                }
+                _ => {
+                    best = Some((index, in_flight));
+                    ties = 1;
+                }
+            }
+        }
+
+        best.map(|(index, _)| index)
+    }
+}
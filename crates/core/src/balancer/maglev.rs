@@ -0,0 +1,80 @@
+use super::hash::hash_bytes;
+use super::{LoadBalancer, PickContext, Random};
+use crate::endpoint::Endpoint;
+use std::sync::Arc;
+
+// Must be prime and much larger than the number of endpoints
+pub const DEFAULT_TABLE_SIZE: usize = 65537;
+
+/// Maglev consistent hashing (Eisenbud et al., NSDI 2016): a lookup table where
+/// each endpoint fills slots following its own permutation, giving even load
+/// and little remapping when endpoints change.
+#[derive(Debug)]
+pub struct Maglev {
+    table: Vec<usize>,
+}
+
+impl Maglev {
+    pub fn new(endpoints: &[Arc<Endpoint>]) -> Self {
+        Self::with_table_size(endpoints, DEFAULT_TABLE_SIZE)
+    }
+
+    pub fn with_table_size(endpoints: &[Arc<Endpoint>], size: usize) -> Self {
+        if endpoints.is_empty() {
+            return Self { table: Vec::new() };
+        }
+
+        let permutations: Vec<(usize, usize)> = endpoints
+            .iter()
+            .map(|endpoint| {
+                let offset = hash_bytes(format!("{}#offset", endpoint.url).as_bytes()) as usize % size;
+                let skip = hash_bytes(format!("{}#skip", endpoint.url).as_bytes()) as usize % (size - 1) + 1;
+                (offset, skip)
+            })
+            .collect();
+
+        let mut table = vec![usize::MAX; size];
+        let mut next = vec![0usize; endpoints.len()];
+        let mut filled = 0;
+
+        'populate: loop {
+            for (index, endpoint) in endpoints.iter().enumerate() {
+                // Heavier endpoints claim more slots per round
+                for _ in 0..endpoint.weight {
+                    let (offset, skip) = permutations[index];
+                    let mut slot = (offset + next[index] * skip) % size;
+                    while table[slot] != usize::MAX {
+                        next[index] += 1;
+                        slot = (offset + next[index] * skip) % size;
+                    }
+                    table[slot] = index;
+                    next[index] += 1;
+                    filled += 1;
+                    if filled == size {
+                        break 'populate;
+                    }
+                }
+            }
+        }
+
+        Self { table }
+    }
+}
+
+impl LoadBalancer for Maglev {
+    fn pick(&self, endpoints: &[Arc<Endpoint>], ctx: &PickContext<'_>) -> Option<usize> {
+        let Some(hash) = ctx.hash else {
+            // Requests without the hash key are spread at random
+            return Random.pick(endpoints, ctx);
+        };
+        if self.table.is_empty() {
+            return None;
+        }
+
+        // Retries walk the following slots until an untried endpoint shows up
+        let start = (hash % self.table.len() as u64) as usize;
+        (0..self.table.len())
+            .map(|offset| self.table[(start + offset) % self.table.len()])
+            .find(|&index| ctx.is_eligible(endpoints, index))
+    }
+}
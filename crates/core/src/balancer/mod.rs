@@ -0,0 +1,61 @@
+use crate::endpoint::Endpoint;
+use leyline_config::LoadBalancingPolicy;
+use std::fmt;
+use std::sync::Arc;
+
+pub mod hash;
+mod least_request;
+mod maglev;
+mod p2c;
+mod random;
+mod ring_hash;
+mod round_robin;
+mod weighted_round_robin;
+
+pub use least_request::LeastRequest;
+pub use maglev::Maglev;
+pub use p2c::P2cEwma;
+pub use random::Random;
+pub use ring_hash::RingHash;
+pub use round_robin::RoundRobin;
+pub use weighted_round_robin::WeightedRoundRobin;
+
+/// Chooses which endpoint of an upstream service serves the next attempt.
+pub trait LoadBalancer: Send + Sync + fmt::Debug {
+    /// Returns the index of the chosen endpoint, or `None` when every eligible
+    /// endpoint has already been tried for this request.
+    fn pick(&self, endpoints: &[Arc<Endpoint>], ctx: &PickContext<'_>) -> Option<usize>;
+}
+
+/// Per-request information available to balancers.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PickContext<'a> {
+    // Endpoints already attempted for this request, in order
+    pub tried: &'a [usize],
+    // Hash of the request's `hash_key`, used by consistent-hash balancers
+    pub hash: Option<u64>,
+    // Set when no endpoint is available, so traffic still flows (panic routing)
+    pub include_unavailable: bool,
+}
+
+impl PickContext<'_> {
+    pub fn is_eligible(&self, endpoints: &[Arc<Endpoint>], index: usize) -> bool {
+        !self.tried.contains(&index) && (self.include_unavailable || endpoints[index].is_available())
+    }
+
+    pub(crate) fn eligible(&self, endpoints: &[Arc<Endpoint>]) -> Vec<usize> {
+        (0..endpoints.len()).filter(|&i| self.is_eligible(endpoints, i)).collect()
+    }
+}
+
+pub fn build(policy: LoadBalancingPolicy, endpoints: &[Arc<Endpoint>]) -> Arc<dyn LoadBalancer> {
+    match policy {
+        LoadBalancingPolicy::RoundRobin => Arc::new(RoundRobin::new()),
+        LoadBalancingPolicy::WeightedRoundRobin => Arc::new(WeightedRoundRobin::new(endpoints.len())),
+        LoadBalancingPolicy::LeastRequest => Arc::new(LeastRequest),
+        LoadBalancingPolicy::Random => Arc::new(Random),
+        LoadBalancingPolicy::P2cEwma => Arc::new(P2cEwma),
+        LoadBalancingPolicy::RingHash => Arc::new(RingHash::new(endpoints)),
+        LoadBalancingPolicy::Maglev => Arc::new(Maglev::new(endpoints)),
+    }
+}
@@ -0,0 +1,36 @@
+use super::{LoadBalancer, PickContext};
+use crate::endpoint::Endpoint;
+use rand::seq::IndexedRandom;
+use std::sync::Arc;
+
+/// Power of two choices: samples two endpoints at random and keeps the one
+/// with the lower EWMA latency weighted by its outstanding requests.
+#[derive(Debug, Default)]
+pub struct P2cEwma;
+
+impl P2cEwma {
+    fn cost(endpoint: &Endpoint) -> f64 {
+        // +1 so endpoints without samples yet still compare by load
+        let latency = endpoint.ewma_latency().as_micros() as f64 + 1.0;
+        latency * (endpoint.in_flight() as f64 + 1.0)
+    }
+}
+
+impl LoadBalancer for P2cEwma {
+    fn pick(&self, endpoints: &[Arc<Endpoint>], ctx: &PickContext<'_>) -> Option<usize> {
+        let eligible = ctx.eligible(endpoints);
+        match eligible.len() {
+            0 => None,
+            1 => Some(eligible[0]),
+            _ => {
+                let sampled: Vec<usize> = eligible.choose_multiple(&mut rand::rng(), 2).copied().collect();
+                let (a, b) = (sampled[0], sampled[1]);
+                if Self::cost(&endpoints[b]) < Self::cost(&endpoints[a]) {
+                    Some(b)
+                } else {
+                    Some(a)
+                }
+            }
+        }
+    }
+}
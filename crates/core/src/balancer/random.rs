@@ -0,0 +1,13 @@
+use super::{LoadBalancer, PickContext};
+use crate::endpoint::Endpoint;
+use rand::seq::IndexedRandom;
+use std::sync::Arc;
+
+#[derive(Debug, Default)]
+pub struct Random;
+
+impl LoadBalancer for Random {
+    fn pick(&self, endpoints: &[Arc<Endpoint>], ctx: &PickContext<'_>) -> Option<usize> {
+        ctx.eligible(endpoints).choose(&mut rand::rng()).copied()
+    }
+}
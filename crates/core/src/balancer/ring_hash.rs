@@ -0,0 +1,57 @@
+use super::hash::hash_bytes;
+use super::{LoadBalancer, PickContext, Random};
+use crate::endpoint::Endpoint;
+use std::sync::Arc;
+
+// Virtual nodes per unit of endpoint weight
+const VIRTUAL_NODES_PER_WEIGHT: u64 = 160;
+// Rings that would be larger are scaled down, keeping the endpoints' proportions
+const MAX_RING_SIZE: u64 = 100_000;
+
+/// Consistent hashing on a ring of virtual nodes. Adding or removing an
+/// endpoint only moves the keys that land next to its virtual nodes.
+#[derive(Debug)]
+pub struct RingHash {
+    // (hash, endpoint index), sorted by hash
+    ring: Vec<(u64, usize)>,
+}
+
+impl RingHash {
+    pub fn new(endpoints: &[Arc<Endpoint>]) -> Self {
+        let nodes: Vec<u64> = endpoints
+            .iter()
+            .map(|endpoint| u64::from(endpoint.weight).saturating_mul(VIRTUAL_NODES_PER_WEIGHT))
+            .collect();
+        let total = nodes.iter().fold(0u64, |total, &count| total.saturating_add(count));
+        let mut ring = Vec::new();
+        for (index, endpoint) in endpoints.iter().enumerate() {
+            let count = match total > MAX_RING_SIZE {
+                // u128 as weights given in code aren't bounded by the configuration
+                true => ((u128::from(nodes[index]) * u128::from(MAX_RING_SIZE) / u128::from(total)) as u64).max(1),
+                false => nodes[index],
+            };
+            // Points are derived from the URL, not the index, so they survive config changes
+            for replica in 0..count {
+                let point = hash_bytes(format!("{}#{}", endpoint.url, replica).as_bytes());
+                ring.push((point, index));
+            }
+        }
+        ring.sort_unstable();
+        Self { ring }
+    }
+}
+
+impl LoadBalancer for RingHash {
+    fn pick(&self, endpoints: &[Arc<Endpoint>], ctx: &PickContext<'_>) -> Option<usize> {
+        let Some(hash) = ctx.hash else {
+            // Requests without the hash key are spread at random
+            return Random.pick(endpoints, ctx);
+        };
+
+        // Walk clockwise from the key to the first endpoint not tried yet
+        let start = self.ring.partition_point(|&(point, _)| point < hash);
+        (0..self.ring.len())
+            .map(|offset| self.ring[(start + offset) % self.ring.len()].1)
+            .find(|&index| ctx.is_eligible(endpoints, index))
+    }
+}
@@ -0,0 +1,39 @@
+use super::{LoadBalancer, PickContext};
+use crate::endpoint::Endpoint;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+#[derive(Debug, Default)]
+pub struct RoundRobin {
+    current: AtomicUsize,
+}
+
+impl RoundRobin {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl LoadBalancer for RoundRobin {
+    fn pick(&self, endpoints: &[Arc<Endpoint>], ctx: &PickContext<'_>) -> Option<usize> {
+        let len = endpoints.len();
+        if len == 0 {
+            return None;
+        }
+
+        // Retries continue from the last tried server
+        if let Some(&last) = ctx.tried.last() {
+            return (1..=len)
+                .map(|offset| (last + offset) % len)
+                .find(|&index| ctx.is_eligible(endpoints, index));
+        }
+
+        // The counter only advances once per request. It rotates over the eligible
+        // servers, so the turns of one that is down are spread over all the others
+        let eligible = ctx.eligible(endpoints);
+        if eligible.is_empty() {
+            return None;
+        }
+        Some(eligible[self.current.fetch_add(1, Ordering::SeqCst) % eligible.len()])
+    }
+}
@@ -0,0 +1,40 @@
+use super::{LoadBalancer, PickContext};
+use crate::endpoint::Endpoint;
+use std::sync::{Arc, Mutex};
+
+/// Smooth weighted round-robin as implemented by nginx: a server with weight 3
+/// next to one with weight 1 gets `a a b a` rather than `a a a b`.
+#[derive(Debug)]
+pub struct WeightedRoundRobin {
+    current_weights: Mutex<Vec<i64>>,
+}
+
+impl WeightedRoundRobin {
+    pub fn new(endpoints: usize) -> Self {
+        Self {
+            current_weights: Mutex::new(vec![0; endpoints]),
+        }
+    }
+}
+
+impl LoadBalancer for WeightedRoundRobin {
+    fn pick(&self, endpoints: &[Arc<Endpoint>], ctx: &PickContext<'_>) -> Option<usize> {
+        let mut current_weights = self.current_weights.lock().unwrap();
+        current_weights.resize(endpoints.len(), 0);
+
+        let mut total = 0;
+        let mut best: Option<usize> = None;
+        for index in ctx.eligible(endpoints) {
+            let weight = endpoints[index].weight as i64;
+            current_weights[index] += weight;
+            total += weight;
+            if best.is_none_or(|best| current_weights[index] > current_weights[best]) {
+                best = Some(index);
+            }
+        }
+
+        let best = best?;
+        current_weights[best] -= total;
+        Some(best)
+    }
+}
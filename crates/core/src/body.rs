@@ -0,0 +1,218 @@
+use crate::endpoint::InFlightGuard;
+use axum::body::{Body, Bytes};
+use axum::http::{header, HeaderMap};
+use futures_util::{stream, StreamExt};
+use http_body_util::BodyExt;
+use hyper::body::{Frame, Incoming, SizeHint};
+use leyline_error::GatewayError;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::time::{Instant, Sleep};
+
+/// A request body read ahead of the first attempt so retries can resend it.
+#[derive(Debug)]
+pub enum RequestBody {
+    // Fully buffered, sent again on every attempt
+    Buffered(Bytes),
+    // Over the replay limit: what was read so far plus the rest of the
+    // client's stream, can only be sent once
+    Streaming(Option<(Bytes, Body)>),
+}
+
+impl RequestBody {
+    /// Buffers up to `limit` bytes of `body`. Bodies announcing a larger
+    /// Content-Length are not read at all.
+    pub async fn read(headers: &HeaderMap, mut body: Body, limit: usize) -> Result<Self, axum::Error> {
+        let content_length = headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<usize>().ok());
+        if content_length.is_some_and(|length| length > limit) {
+            return Ok(RequestBody::Streaming(Some((Bytes::new(), body))));
+        }
+
+        let mut buffered = Vec::new();
+        while let Some(frame) = body.frame().await {
+            // Trailers are not forwarded
+            let Ok(data) = frame?.into_data() else { continue };
+            buffered.extend_from_slice(&data);
+            if buffered.len() > limit {
+                return Ok(RequestBody::Streaming(Some((Bytes::from(buffered), body))));
+            }
+        }
+        Ok(RequestBody::Buffered(Bytes::from(buffered)))
+    }
+
+    pub fn is_replayable(&self) -> bool {
+        matches!(self, RequestBody::Buffered(_))
+    }
+
+    /// Length of a buffered body, streamed bodies keep the client's framing.
+    pub fn buffered_len(&self) -> Option<usize> {
+        match self {
+            RequestBody::Buffered(bytes) => Some(bytes.len()),
+            RequestBody::Streaming(_) => None,
+        }
+    }
+
+    /// The body for the next attempt, `None` once a streamed body has been sent.
+    pub fn take(&mut self) -> Option<Body> {
+        match self {
+            RequestBody::Buffered(bytes) => Some(Body::from(bytes.clone())),
+            RequestBody::Streaming(parts) => {
+                let (prefix, rest) = parts.take()?;
+                let prefix = stream::once(async move { Ok::<_, axum::Error>(prefix) });
+                // Pulled from the client only as fast as the upstream accepts it
+                Some(Body::from_stream(prefix.chain(rest.into_data_stream())))
+            }
+        }
+    }
+}
+
+/// An upstream response body streamed through to the client frame by frame,
+/// failing if the upstream stalls or the request deadline passes. Dropping it,
+/// e.g. when the client disconnects, closes the upstream connection.
+pub struct ResponseBody {
+    inner: Incoming,
+    upstream_url: String,
+    idle: Option<Duration>,
+    // Long-lived streams have none
+    deadline: Option<Instant>,
+    timer: Option<Pin<Box<Sleep>>>,
+    done: bool,
+    // The upstream request stays in flight until its body is fully sent
+    _in_flight: Option<InFlightGuard>,
+}
+
+impl ResponseBody {
+    pub fn new(
+        inner: Incoming,
+        upstream_url: impl Into<String>,
+        idle: Option<Duration>,
+        deadline: Option<Instant>,
+        in_flight: Option<InFlightGuard>,
+    ) -> Self {
+        let timer = next_timeout(idle, deadline).map(|timeout| Box::pin(tokio::time::sleep_until(timeout)));
+        Self {
+            inner,
+            upstream_url: upstream_url.into(),
+            idle,
+            deadline,
+            timer,
+            done: false,
+            _in_flight: in_flight,
+        }
+    }
+}
+
+fn next_timeout(idle: Option<Duration>, deadline: Option<Instant>) -> Option<Instant> {
+    let idle = idle.map(|idle| Instant::now() + idle);
+    match (idle, deadline) {
+        (Some(idle), Some(deadline)) => Some(idle.min(deadline)),
+        (idle, deadline) => idle.or(deadline),
+    }
+}
+
+impl hyper::body::Body for ResponseBody {
+    type Data = Bytes;
+    type Error = GatewayError;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, GatewayError>>> {
+        if self.done {
+            return Poll::Ready(None);
+        }
+
+        if let Poll::Ready(frame) = Pin::new(&mut self.inner).poll_frame(cx) {
+            match frame {
+                Some(Ok(frame)) => {
+                    if let Some(next) = next_timeout(self.idle, self.deadline)
+                        && let Some(timer) = &mut self.timer
+                    {
+                        timer.as_mut().reset(next);
+                    }
+                    return Poll::Ready(Some(Ok(frame)));
+                }
+                Some(Err(e)) => {
+                    self.done = true;
+                    tracing::warn!("reading response from upstream server {} failed: {}", self.upstream_url, e);
+                    return Poll::Ready(Some(Err(GatewayError::UpstreamBody(e))));
+                }
+                None => {
+                    self.done = true;
+                    return Poll::Ready(None);
+                }
+            }
+        }
+
+        let Some(timer) = &mut self.timer else {
+            return Poll::Pending;
+        };
+        ready!(timer.as_mut().poll(cx));
+        self.done = true;
+        let error = if self.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            GatewayError::DeadlineExceeded
+        } else {
+            GatewayError::BodyIdleTimeout(self.upstream_url.clone())
+        };
+        tracing::warn!("response from upstream server {} cut off: {}", self.upstream_url, error);
+        Poll::Ready(Some(Err(error)))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.done || self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for ResponseBody {
+    fn drop(&mut self) {
+        if !self.done && !hyper::body::Body::is_end_stream(&self.inner) {
+            tracing::debug!("client went away, closing the response from upstream server {}", self.upstream_url);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(body: &'static str, limit: usize) -> RequestBody {
+        RequestBody::read(&HeaderMap::new(), Body::from(body), limit).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_buffered_body_is_replayed() {
+        let mut body = read("hello", 5).await;
+        assert!(body.is_replayable());
+        assert_eq!(body.buffered_len(), Some(5));
+        for _ in 0..3 {
+            let bytes = body.take().unwrap().collect().await.unwrap().to_bytes();
+            assert_eq!(bytes, "hello");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_large_body_is_sent_once() {
+        let mut body = read("hello world", 5).await;
+        assert!(!body.is_replayable());
+        assert_eq!(body.buffered_len(), None);
+        assert!(body.take().is_some());
+        assert!(body.take().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_content_length_over_limit_is_not_read() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_LENGTH, "11".parse().unwrap());
+        let body = RequestBody::read(&headers, Body::from("hello world"), 5).await.unwrap();
+        match body {
+            RequestBody::Streaming(Some((prefix, _))) => assert!(prefix.is_empty()),
+            other => panic!("expected a streamed body, got {:?}", other),
+        }
+    }
+}
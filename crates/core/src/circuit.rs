@@ -0,0 +1,217 @@
+use leyline_config::CircuitBreakerConfig;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Retry-After suggested while every half-open probe slot is taken
+const PROBE_BUSY_RETRY: Duration = Duration::from_secs(1);
+
+/// Closed/open/half-open circuit breaker guarding a service or a single upstream.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    name: String,
+    config: CircuitBreakerConfig,
+    state: Mutex<CircuitState>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CircuitState {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    HalfOpen { probes: u32, successes: u32 },
+}
+
+impl CircuitBreaker {
+    pub fn new(name: impl Into<String>, config: &CircuitBreakerConfig) -> Self {
+        Self {
+            name: name.into(),
+            config: config.clone(),
+            state: Mutex::new(CircuitState::Closed { failures: 0 }),
+        }
+    }
+
+    /// Whether a request sent now would be let through.
+    pub fn allows_requests(&self) -> bool {
+        self.allows_requests_at(Instant::now())
+    }
+
+    pub fn allows_requests_at(&self, now: Instant) -> bool {
+        match *self.state.lock().unwrap() {
+            CircuitState::Closed { .. } => true,
+            CircuitState::Open { until } => until <= now,
+            CircuitState::HalfOpen { probes, .. } => probes < self.config.half_open_max_requests,
+        }
+    }
+
+    /// Lets a request through, or returns how long the caller should wait.
+    pub fn try_acquire(&self) -> Result<CircuitPermit<'_>, Duration> {
+        self.try_acquire_at(Instant::now())
+    }
+
+    pub fn try_acquire_at(&self, now: Instant) -> Result<CircuitPermit<'_>, Duration> {
+        let mut state = self.state.lock().unwrap();
+        if let CircuitState::Open { until } = *state {
+            if until > now {
+                return Err(until - now);
+            }
+            tracing::info!("circuit for {} is half-open, letting probes through", self.name);
+            *state = CircuitState::HalfOpen { probes: 0, successes: 0 };
+        }
+
+        let probe = match &mut *state {
+            CircuitState::HalfOpen { probes, .. } if *probes >= self.config.half_open_max_requests => {
+                return Err(PROBE_BUSY_RETRY);
+            }
+            CircuitState::HalfOpen { probes, .. } => {
+                *probes += 1;
+                true
+            }
+            _ => false,
+        };
+        Ok(CircuitPermit { breaker: self, probe })
+    }
+
+    fn record_at(&self, success: bool, probe: bool, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        match &mut *state {
+            CircuitState::Closed { failures } => {
+                if success {
+                    *failures = 0;
+                } else {
+                    *failures += 1;
+                    if *failures >= self.config.failure_threshold {
+                        tracing::warn!("circuit for {} opened after {} consecutive failures", self.name, failures);
+                        *state = self.open(now);
+                    }
+                }
+            }
+            // Requests let through before the circuit changed state don't count
+            CircuitState::HalfOpen { probes, successes } if probe => {
+                *probes -= 1;
+                if !success {
+                    tracing::warn!("probe for {} failed, circuit opened again", self.name);
+                    *state = self.open(now);
+                } else {
+                    *successes += 1;
+                    if *successes >= self.config.success_threshold {
+                        tracing::info!("circuit for {} closed", self.name);
+                        *state = CircuitState::Closed { failures: 0 };
+                    }
+                }
+            }
+            CircuitState::HalfOpen { .. } | CircuitState::Open { .. } => {}
+        }
+    }
+
+    fn open(&self, now: Instant) -> CircuitState {
+        CircuitState::Open { until: now + Duration::from_secs(self.config.open_seconds) }
+    }
+
+    fn release_probe(&self) {
+        if let CircuitState::HalfOpen { probes, .. } = &mut *self.state.lock().unwrap() {
+            *probes = probes.saturating_sub(1);
+        }
+    }
+}
+
+/// A request admitted by a circuit breaker. Dropping it without recording an
+/// outcome, e.g. because the client went away, frees its half-open probe slot.
+#[derive(Debug)]
+pub struct CircuitPermit<'a> {
+    breaker: &'a CircuitBreaker,
+    probe: bool,
+}
+
+impl CircuitPermit<'_> {
+    pub fn record(self, success: bool) {
+        self.record_at(success, Instant::now());
+    }
+
+    pub fn record_at(mut self, success: bool, now: Instant) {
+        self.breaker.record_at(success, self.probe, now);
+        self.probe = false;
+    }
+}
+
+impl Drop for CircuitPermit<'_> {
+    fn drop(&mut self) {
+        if self.probe {
+            self.breaker.release_probe();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker() -> CircuitBreaker {
+        CircuitBreaker::new("/py", &CircuitBreakerConfig {
+            failure_threshold: 3,
+            open_seconds: 30,
+            half_open_max_requests: 1,
+            success_threshold: 2,
+        })
+    }
+
+    fn fail(breaker: &CircuitBreaker, times: usize, now: Instant) {
+        for _ in 0..times {
+            breaker.try_acquire_at(now).unwrap().record_at(false, now);
+        }
+    }
+
+    #[test]
+    fn test_opens_after_consecutive_failures() {
+        let breaker = breaker();
+        let now = Instant::now();
+
+        fail(&breaker, 2, now);
+        breaker.try_acquire_at(now).unwrap().record_at(true, now);
+        fail(&breaker, 2, now);
+        assert!(breaker.allows_requests_at(now));
+
+        fail(&breaker, 1, now);
+        assert!(!breaker.allows_requests_at(now));
+        assert_eq!(breaker.try_acquire_at(now).unwrap_err(), Duration::from_secs(30));
+        let later = now + Duration::from_secs(10);
+        assert_eq!(breaker.try_acquire_at(later).unwrap_err(), Duration::from_secs(20));
+    }
+
+    #[test]
+    fn test_half_open_probes_close_the_circuit() {
+        let breaker = breaker();
+        let now = Instant::now();
+        fail(&breaker, 3, now);
+
+        let now = now + Duration::from_secs(30);
+        let probe = breaker.try_acquire_at(now).unwrap();
+        // Only one probe at a time
+        assert_eq!(breaker.try_acquire_at(now).unwrap_err(), PROBE_BUSY_RETRY);
+        probe.record_at(true, now);
+        assert!(breaker.allows_requests_at(now));
+
+        breaker.try_acquire_at(now).unwrap().record_at(true, now);
+        assert_eq!(*breaker.state.lock().unwrap(), CircuitState::Closed { failures: 0 });
+    }
+
+    #[test]
+    fn test_failed_probe_reopens() {
+        let breaker = breaker();
+        let now = Instant::now();
+        fail(&breaker, 3, now);
+
+        let now = now + Duration::from_secs(30);
+        breaker.try_acquire_at(now).unwrap().record_at(false, now);
+        assert_eq!(breaker.try_acquire_at(now).unwrap_err(), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_dropped_probe_frees_its_slot() {
+        let breaker = breaker();
+        let now = Instant::now();
+        fail(&breaker, 3, now);
+
+        let now = now + Duration::from_secs(30);
+        drop(breaker.try_acquire_at(now).unwrap());
+        assert!(breaker.try_acquire_at(now).is_ok());
+    }
+}
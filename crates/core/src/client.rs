@@ -0,0 +1,63 @@
+use crate::profile::Profile;
+use axum::body::Body;
+use hyper_tls::HttpsConnector;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use leyline_error::GatewayError;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Client for upstream requests, streaming bodies in both directions.
+pub type HttpClient = Client<HttpsConnector<HttpConnector>, Body>;
+
+/// HTTP clients for upstream requests. The connect timeout is a setting of the
+/// connector, so there is one client (and connection pool) per distinct timeout.
+#[derive(Debug)]
+pub struct UpstreamClients {
+    connection_pooling: bool,
+    clients: Mutex<HashMap<Option<Duration>, HttpClient>>,
+}
+
+impl UpstreamClients {
+    pub fn new(profile: &Profile) -> Self {
+        Self {
+            connection_pooling: profile.connection_pooling,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(&self, connect_timeout: Option<Duration>) -> Result<HttpClient, GatewayError> {
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(client) = clients.get(&connect_timeout) {
+            return Ok(client.clone());
+        }
+
+        let client = build_client(connect_timeout, self.connection_pooling)?;
+        clients.insert(connect_timeout, client.clone());
+        Ok(client)
+    }
+}
+
+/// Client for health check probes, each probe opens a connection of its own.
+pub fn health_check_client(connect_timeout: Duration) -> Result<HttpClient, GatewayError> {
+    build_client(Some(connect_timeout), false)
+}
+
+fn build_client(connect_timeout: Option<Duration>, connection_pooling: bool) -> Result<HttpClient, GatewayError> {
+    let mut http = HttpConnector::new();
+    http.enforce_http(false);  // https URLs are handed to the TLS connector
+    http.set_nodelay(true);
+    http.set_connect_timeout(connect_timeout);
+    let tls = hyper_tls::native_tls::TlsConnector::new()?;
+
+    // Responses are forwarded exactly as the upstream sent them: hyper neither
+    // decompresses bodies nor follows redirects
+    let mut client_builder = Client::builder(TokioExecutor::new());
+    if !connection_pooling {
+        client_builder.pool_max_idle_per_host(0);  // Disable connection pooling to prevent socket issues
+    }
+
+    Ok(client_builder.build(HttpsConnector::from((http, tls.into()))))
+}
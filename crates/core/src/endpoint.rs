@@ -0,0 +1,156 @@
+use crate::circuit::CircuitBreaker;
+use leyline_config::CircuitBreakerConfig;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// How quickly old latency samples fade out of the EWMA
+const EWMA_DECAY: Duration = Duration::from_secs(10);
+
+/// A single upstream server together with the load statistics balancers read.
+#[derive(Debug)]
+pub struct Endpoint {
+    pub url: String,
+    pub weight: u32,
+    in_flight: AtomicUsize,
+    latency: Mutex<Ewma>,
+    // Maintained by the active health checker, endpoints start out healthy
+    healthy: AtomicBool,
+    // Set by outlier detection
+    ejected_until: Mutex<Option<Instant>>,
+    circuit_breaker: Option<CircuitBreaker>,
+}
+
+#[derive(Debug, Default)]
+struct Ewma {
+    micros: f64,
+    updated_at: Option<Instant>,
+}
+
+impl Endpoint {
+    pub fn new(url: impl Into<String>, weight: u32) -> Self {
+        Self {
+            url: url.into(),
+            weight,
+            in_flight: AtomicUsize::new(0),
+            latency: Mutex::new(Ewma::default()),
+            healthy: AtomicBool::new(true),
+            ejected_until: Mutex::new(None),
+            circuit_breaker: None,
+        }
+    }
+
+    pub fn with_circuit_breaker(mut self, config: &CircuitBreakerConfig) -> Self {
+        self.circuit_breaker = Some(CircuitBreaker::new(self.url.clone(), config));
+        self
+    }
+
+    pub fn circuit_breaker(&self) -> Option<&CircuitBreaker> {
+        self.circuit_breaker.as_ref()
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    pub fn set_healthy(&self, healthy: bool) {
+        self.healthy.store(healthy, Ordering::Relaxed);
+    }
+
+    pub fn is_ejected(&self) -> bool {
+        self.is_ejected_at(Instant::now())
+    }
+
+    pub fn is_ejected_at(&self, now: Instant) -> bool {
+        self.ejected_until.lock().unwrap().is_some_and(|until| until > now)
+    }
+
+    pub fn eject_until(&self, until: Instant) {
+        *self.ejected_until.lock().unwrap() = Some(until);
+    }
+
+    /// Clears an expired ejection, returning true if there was one.
+    pub fn clear_expired_ejection(&self, now: Instant) -> bool {
+        let mut ejected_until = self.ejected_until.lock().unwrap();
+        match *ejected_until {
+            Some(until) if until <= now => {
+                *ejected_until = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether balancers should send new requests here.
+    pub fn is_available(&self) -> bool {
+        self.is_healthy()
+            && !self.is_ejected()
+            && self.circuit_breaker.as_ref().is_none_or(|circuit| circuit.allows_requests())
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// Exponentially weighted moving average of recent request latencies,
+    /// zero until the first response or failure.
+    pub fn ewma_latency(&self) -> Duration {
+        let ewma = self.latency.lock().unwrap();
+        Duration::from_micros(ewma.micros as u64)
+    }
+
+    pub fn record_latency(&self, latency: Duration) {
+        let now = Instant::now();
+        let sample = latency.as_micros() as f64;
+        let mut ewma = self.latency.lock().unwrap();
+        ewma.micros = match ewma.updated_at {
+            None => sample,
+            Some(updated_at) => {
+                // Weight the previous average by how long ago it was updated
+                let elapsed = now.duration_since(updated_at).as_secs_f64();
+                let w = (-elapsed / EWMA_DECAY.as_secs_f64()).exp();
+                ewma.micros * w + sample * (1.0 - w)
+            }
+        };
+        ewma.updated_at = Some(now);
+    }
+
+    /// Counts a request as in flight until the guard is dropped, which for
+    /// proxied requests is once the response body has been sent.
+    pub fn start_request(self: &Arc<Self>) -> InFlightGuard {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlightGuard { endpoint: self.clone() }
+    }
+
+    /// Measures the latency of a request until the timer is dropped, which
+    /// should happen once the response headers arrived or the attempt failed.
+    pub fn start_timer(self: &Arc<Self>) -> LatencyTimer {
+        LatencyTimer {
+            endpoint: self.clone(),
+            started_at: Instant::now(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct InFlightGuard {
+    endpoint: Arc<Endpoint>,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.endpoint.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug)]
+pub struct LatencyTimer {
+    endpoint: Arc<Endpoint>,
+    started_at: Instant,
+}
+
+impl Drop for LatencyTimer {
+    fn drop(&mut self) {
+        self.endpoint.record_latency(self.started_at.elapsed());
+    }
+}
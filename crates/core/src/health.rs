@@ -0,0 +1,265 @@
+use crate::client::{health_check_client, HttpClient};
+use crate::endpoint::Endpoint;
+use axum::body::Body;
+use axum::http::{Request, Uri};
+use leyline_config::{HealthCheckConfig, HealthCheckKind};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// Background health checks for the endpoints of one service.
+///
+/// The probe tasks are aborted when the handle is dropped or stopped, a reload
+/// stops them as soon as it has replaced the service.
+#[derive(Debug)]
+pub struct HealthChecker {
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl HealthChecker {
+    pub fn spawn(prefix: &str, endpoints: &[Arc<Endpoint>], config: &HealthCheckConfig) -> Self {
+        // Only fails when TLS can't be set up, every HTTP check then fails with the reason
+        let client = health_check_client(Duration::from_secs(config.timeout_seconds)).map_err(|e| e.to_string());
+
+        let tasks = endpoints
+            .iter()
+            .map(|endpoint| {
+                let probe = Probe {
+                    prefix: prefix.to_string(),
+                    endpoint: endpoint.clone(),
+                    config: config.clone(),
+                    client: client.clone(),
+                };
+                tokio::spawn(probe.run())
+            })
+            .collect();
+
+        Self { tasks }
+    }
+
+    /// Whether every probe task has ended, which only happens once stopped.
+    pub fn is_stopped(&self) -> bool {
+        self.tasks.iter().all(JoinHandle::is_finished)
+    }
+
+    pub fn stop(&self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+impl Drop for HealthChecker {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Consecutive success/failure counting with separate thresholds.
+#[derive(Debug)]
+pub struct HealthState {
+    healthy: bool,
+    successes: u32,
+    failures: u32,
+    healthy_threshold: u32,
+    unhealthy_threshold: u32,
+}
+
+impl HealthState {
+    pub fn new(healthy_threshold: u32, unhealthy_threshold: u32) -> Self {
+        Self {
+            healthy: true,
+            successes: 0,
+            failures: 0,
+            healthy_threshold,
+            unhealthy_threshold,
+        }
+    }
+
+    /// Records a probe result and returns the new health when it flipped.
+    pub fn record(&mut self, success: bool) -> Option<bool> {
+        if success {
+            self.successes += 1;
+            self.failures = 0;
+            if !self.healthy && self.successes >= self.healthy_threshold {
+                self.healthy = true;
+                return Some(true);
+            }
+        } else {
+            self.failures += 1;
+            self.successes = 0;
+            if self.healthy && self.failures >= self.unhealthy_threshold {
+                self.healthy = false;
+                return Some(false);
+            }
+        }
+        None
+    }
+}
+
+struct Probe {
+    prefix: String,
+    endpoint: Arc<Endpoint>,
+    config: HealthCheckConfig,
+    client: Result<HttpClient, String>,
+}
+
+impl Probe {
+    async fn run(self) {
+        let mut state = HealthState::new(self.config.healthy_threshold, self.config.unhealthy_threshold);
+        // Endpoints kept across a reload start out the way the previous checks left them
+        state.healthy = self.endpoint.is_healthy();
+        let mut interval = tokio::time::interval(Duration::from_secs(self.config.interval_seconds));
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+
+            let result = self.check().await;
+            if let Err(reason) = &result {
+                tracing::debug!("health check for {} failed: {}", self.endpoint.url, reason);
+            }
+
+            match state.record(result.is_ok()) {
+                Some(true) => {
+                    tracing::info!("upstream {} of {} is healthy again", self.endpoint.url, self.prefix);
+                    self.endpoint.set_healthy(true);
+                }
+                Some(false) => {
+                    tracing::warn!("upstream {} of {} marked unhealthy", self.endpoint.url, self.prefix);
+                    self.endpoint.set_healthy(false);
+                }
+                None => {}
+            }
+        }
+    }
+
+    async fn check(&self) -> Result<(), String> {
+        match self.config.kind {
+            HealthCheckKind::Http => {
+                let client = self.client.as_ref().map_err(Clone::clone)?;
+                let url = format!("{}{}", self.endpoint.url.trim_end_matches('/'), self.config.path);
+                let uri: Uri = url.parse().map_err(|e| format!("invalid URL: {}", e))?;
+                let request = Request::get(uri).body(Body::empty()).map_err(|e| e.to_string())?;
+                // The response body is never read, redirects count as healthy
+                let timeout = Duration::from_secs(self.config.timeout_seconds);
+                let response = match tokio::time::timeout(timeout, client.request(request)).await {
+                    Ok(response) => response.map_err(|e| e.to_string())?,
+                    Err(_) => return Err(format!("timed out after {:?}", timeout)),
+                };
+                let status = response.status();
+                if status.is_success() || status.is_redirection() {
+                    Ok(())
+                } else {
+                    Err(format!("status {}", status))
+                }
+            }
+            HealthCheckKind::Tcp => {
+                let address = socket_address(&self.endpoint.url)?;
+                let timeout = Duration::from_secs(self.config.timeout_seconds);
+                match tokio::time::timeout(timeout, tokio::net::TcpStream::connect(&address)).await {
+                    Ok(Ok(_)) => Ok(()),
+                    Ok(Err(e)) => Err(e.to_string()),
+                    Err(_) => Err(format!("connect timed out after {:?}", timeout)),
+                }
+            }
+        }
+    }
+}
+
+fn socket_address(url: &str) -> Result<String, String> {
+    let uri: axum::http::Uri = url.parse().map_err(|e| format!("invalid URL: {}", e))?;
+    let host = uri.host().ok_or("missing host")?;
+    let port = uri.port_u16().unwrap_or(if uri.scheme_str() == Some("https") { 443 } else { 80 });
+    Ok(format!("{}:{}", host.trim_start_matches('[').trim_end_matches(']'), port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_thresholds() {
+        let mut state = HealthState::new(2, 3);
+
+        assert_eq!(state.record(false), None);
+        assert_eq!(state.record(false), None);
+        assert_eq!(state.record(true), None);
+        // A success resets the failure streak
+        assert_eq!(state.record(false), None);
+        assert_eq!(state.record(false), None);
+        assert_eq!(state.record(false), Some(false));
+        assert_eq!(state.record(false), None);
+
+        assert_eq!(state.record(true), None);
+        assert_eq!(state.record(true), Some(true));
+        assert_eq!(state.record(true), None);
+    }
+
+    #[test]
+    fn test_socket_address() {
+        assert_eq!(socket_address("http://127.0.0.1:8081").unwrap(), "127.0.0.1:8081");
+        assert_eq!(socket_address("http://example.com").unwrap(), "example.com:80");
+        assert_eq!(socket_address("https://example.com/base").unwrap(), "example.com:443");
+    }
+
+    #[tokio::test]
+    async fn test_tcp_check() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let endpoint = Arc::new(Endpoint::new(format!("http://{}", address), 1));
+        let config = HealthCheckConfig {
+            kind: HealthCheckKind::Tcp,
+            path: "/health".to_string(),
+            interval_seconds: 1,
+            timeout_seconds: 1,
+            healthy_threshold: 1,
+            unhealthy_threshold: 1,
+        };
+        let probe = Probe {
+            prefix: "/py".to_string(),
+            endpoint: endpoint.clone(),
+            config,
+            client: health_check_client(Duration::from_secs(1)).map_err(|e| e.to_string()),
+        };
+
+        assert_eq!(probe.check().await, Ok(()));
+        drop(listener);
+        assert!(probe.check().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_http_check() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            for status in ["200 OK", "302 Found", "503 Service Unavailable"] {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = [0; 1024];
+                let read = stream.read(&mut request).await.unwrap();
+                assert!(request[..read].starts_with(b"GET /base/health HTTP/1.1\r\n"));
+                let response = format!("HTTP/1.1 {}\r\ncontent-length: 0\r\n\r\n", status);
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        let probe = Probe {
+            prefix: "/py".to_string(),
+            endpoint: Arc::new(Endpoint::new(format!("http://{}/base/", address), 1)),
+            config: HealthCheckConfig {
+                kind: HealthCheckKind::Http,
+                path: "/health".to_string(),
+                interval_seconds: 1,
+                timeout_seconds: 1,
+                healthy_threshold: 1,
+                unhealthy_threshold: 1,
+            },
+            client: health_check_client(Duration::from_secs(1)).map_err(|e| e.to_string()),
+        };
+
+        assert_eq!(probe.check().await, Ok(()));
+        assert_eq!(probe.check().await, Ok(()));
+        assert_eq!(probe.check().await, Err("status 503 Service Unavailable".to_string()));
+    }
+}
@@ -0,0 +1,94 @@
+use axum::http::Method;
+use leyline_config::HedgeConfig;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+
+// Recent latencies kept for the percentile, and how many are needed to use it
+const MAX_SAMPLES: usize = 256;
+const MIN_SAMPLES: usize = 20;
+
+/// When to send a hedged attempt for a service.
+#[derive(Debug)]
+pub struct HedgePolicy {
+    config: HedgeConfig,
+    samples: Mutex<VecDeque<Duration>>,
+}
+
+impl HedgePolicy {
+    pub fn new(config: &HedgeConfig) -> Self {
+        Self {
+            config: config.clone(),
+            samples: Mutex::new(VecDeque::with_capacity(MAX_SAMPLES)),
+        }
+    }
+
+    /// Only requests that are safe to send twice are hedged.
+    pub fn applies_to(method: &Method) -> bool {
+        matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE)
+    }
+
+    /// How long to wait for the first attempt before hedging.
+    pub fn delay(&self) -> Duration {
+        let fixed = Duration::from_millis(self.config.delay_ms);
+        let Some(percentile) = self.config.percentile else {
+            return fixed;
+        };
+
+        let samples = self.samples.lock().unwrap();
+        if samples.len() < MIN_SAMPLES {
+            return fixed;
+        }
+        let mut sorted: Vec<Duration> = samples.iter().copied().collect();
+        sorted.sort_unstable();
+        let rank = (sorted.len() * percentile as usize).div_ceil(100);
+        sorted[rank.saturating_sub(1)]
+    }
+
+    /// Records how long an attempt took to get response headers.
+    pub fn record_latency(&self, latency: Duration) {
+        if self.config.percentile.is_none() {
+            return;
+        }
+        let mut samples = self.samples.lock().unwrap();
+        if samples.len() == MAX_SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back(latency);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fixed_delay() {
+        let policy = HedgePolicy::new(&HedgeConfig { delay_ms: 50, percentile: None });
+        policy.record_latency(Duration::from_secs(1));
+        assert_eq!(policy.delay(), Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_percentile_delay() {
+        let policy = HedgePolicy::new(&HedgeConfig { delay_ms: 50, percentile: Some(90) });
+        for ms in 1..MIN_SAMPLES as u64 {
+            policy.record_latency(Duration::from_millis(ms));
+        }
+        // Not enough samples yet
+        assert_eq!(policy.delay(), Duration::from_millis(50));
+
+        for ms in MIN_SAMPLES as u64..=100 {
+            policy.record_latency(Duration::from_millis(ms));
+        }
+        assert_eq!(policy.delay(), Duration::from_millis(90));
+    }
+
+    #[test]
+    fn test_safe_methods() {
+        assert!(HedgePolicy::applies_to(&Method::GET));
+        assert!(HedgePolicy::applies_to(&Method::HEAD));
+        assert!(!HedgePolicy::applies_to(&Method::POST));
+        assert!(!HedgePolicy::applies_to(&Method::PUT));
+    }
+}
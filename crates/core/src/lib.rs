@@ -0,0 +1,27 @@
+//! Proxy engine shared by the `leyline-rabbit` and `leyline-envoy` binaries.
+
+pub mod admin;
+pub mod balancer;
+pub mod body;
+pub mod circuit;
+pub mod client;
+pub mod endpoint;
+pub mod health;
+pub mod hedge;
+pub mod matcher;
+pub mod metrics;
+pub mod outlier;
+pub mod profile;
+pub mod proxy;
+pub mod retry;
+pub mod rewrite;
+pub mod routing;
+pub mod server;
+pub mod service;
+pub mod split;
+pub mod sticky;
+pub mod telemetry;
+pub mod upgrade;
+
+pub use leyline_config as config;
+pub use leyline_error::GatewayError;
@@ -0,0 +1,177 @@
+use axum::http::{header, HeaderMap, HeaderName, Method, Uri};
+use leyline_config::{FieldMatchConfig, RouteMatchConfig};
+use regex::Regex;
+
+/// Conditions a request has to meet, on top of its path, to be routed to a
+/// service. The default matches every request.
+#[derive(Debug, Default)]
+pub struct RouteMatcher {
+    hosts: Vec<HostMatch>,
+    methods: Vec<Method>,
+    headers: Vec<(HeaderName, ValueMatch)>,
+    query: Vec<(String, ValueMatch)>,
+    pub priority: i32,
+}
+
+#[derive(Debug)]
+enum HostMatch {
+    Exact(String),
+    // `*.example.com`, stored as `.example.com`
+    Subdomain(String),
+}
+
+#[derive(Debug)]
+enum ValueMatch {
+    Present,
+    Exact(String),
+    Regex(Regex),
+}
+
+impl RouteMatcher {
+    /// Builds the matcher from a validated configuration.
+    pub fn from_config(config: &RouteMatchConfig) -> Self {
+        let hosts = config.hosts
+            .iter()
+            .map(|host| match host.strip_prefix('*') {
+                Some(suffix) => HostMatch::Subdomain(suffix.to_ascii_lowercase()),
+                None => HostMatch::Exact(host.to_ascii_lowercase()),
+            })
+            .collect();
+        let methods = config.methods
+            .iter()
+            .filter_map(|method| Method::from_bytes(method.as_bytes()).ok())
+            .collect();
+        let headers = config.headers
+            .iter()
+            .filter_map(|field| Some((HeaderName::from_bytes(field.name.as_bytes()).ok()?, ValueMatch::from_config(field)?)))
+            .collect();
+        let query = config.query
+            .iter()
+            .filter_map(|field| Some((field.name.clone(), ValueMatch::from_config(field)?)))
+            .collect();
+        Self { hosts, methods, headers, query, priority: config.priority }
+    }
+
+    pub fn matches(&self, method: &Method, uri: &Uri, headers: &HeaderMap) -> bool {
+        if !self.methods.is_empty() && !self.methods.contains(method) {
+            return false;
+        }
+        if !self.hosts.is_empty() {
+            let Some(host) = request_host(uri, headers) else { return false };
+            if !self.hosts.iter().any(|pattern| pattern.matches(&host)) {
+                return false;
+            }
+        }
+        let headers_match = self.headers.iter().all(|(name, value)| {
+            headers.get_all(name).iter().any(|header| header.to_str().is_ok_and(|header| value.matches(header)))
+        });
+        if !headers_match {
+            return false;
+        }
+        self.query.iter().all(|(name, value)| {
+            let query = uri.query().unwrap_or_default();
+            form_urlencoded::parse(query.as_bytes()).any(|(param, param_value)| param == *name && value.matches(&param_value))
+        })
+    }
+}
+
+impl HostMatch {
+    fn matches(&self, host: &str) -> bool {
+        match self {
+            HostMatch::Exact(name) => host == name,
+            HostMatch::Subdomain(suffix) => host.len() > suffix.len() && host.ends_with(suffix.as_str()),
+        }
+    }
+}
+
+impl ValueMatch {
+    fn from_config(config: &FieldMatchConfig) -> Option<Self> {
+        Some(match (&config.value, &config.regex) {
+            (Some(value), _) => ValueMatch::Exact(value.clone()),
+            (None, Some(regex)) => ValueMatch::Regex(Regex::new(regex).ok()?),
+            (None, None) => ValueMatch::Present,
+        })
+    }
+
+    fn matches(&self, value: &str) -> bool {
+        match self {
+            ValueMatch::Present => true,
+            ValueMatch::Exact(expected) => value == expected,
+            ValueMatch::Regex(regex) => regex.is_match(value),
+        }
+    }
+}
+
+/// The host a request is for, lowercased and without a port. HTTP/2 requests
+/// carry it in the URI, HTTP/1.1 ones in the `Host` header.
+fn request_host(uri: &Uri, headers: &HeaderMap) -> Option<String> {
+    let host = match uri.host() {
+        Some(host) => host,
+        None => {
+            let authority = headers.get(header::HOST)?.to_str().ok()?;
+            match authority.rsplit_once(':') {
+                Some((host, port)) if !port.contains(']') => host,
+                _ => authority,
+            }
+        }
+    };
+    Some(host.trim_end_matches('.').to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    fn field(name: &str, value: Option<&str>, regex: Option<&str>) -> FieldMatchConfig {
+        FieldMatchConfig { name: name.to_string(), value: value.map(String::from), regex: regex.map(String::from) }
+    }
+
+    fn request(method: &str, uri: &str, headers: &[(&str, &str)]) -> (Method, Uri, HeaderMap) {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.append(HeaderName::from_bytes(name.as_bytes()).unwrap(), value.parse().unwrap());
+        }
+        (method.parse().unwrap(), uri.parse().unwrap(), map)
+    }
+
+    fn matches(matcher: &RouteMatcher, method: &str, uri: &str, headers: &[(&str, &str)]) -> bool {
+        let (method, uri, headers) = request(method, uri, headers);
+        matcher.matches(&method, &uri, &headers)
+    }
+
+    #[test]
+    fn test_matches_hosts() {
+        let matcher = RouteMatcher::from_config(&RouteMatchConfig {
+            hosts: strings(&["api.example.com", "*.example.org"]),
+            ..Default::default()
+        });
+        assert!(matches(&matcher, "GET", "/", &[("host", "API.example.com:8080")]));
+        assert!(matches(&matcher, "GET", "http://eu.example.org/", &[]));
+        assert!(matches(&matcher, "GET", "/", &[("host", "a.b.example.org")]));
+        assert!(!matches(&matcher, "GET", "/", &[("host", "example.org")]));
+        assert!(!matches(&matcher, "GET", "/", &[("host", "www.example.com")]));
+        assert!(!matches(&matcher, "GET", "/", &[]));
+    }
+
+    #[test]
+    fn test_matches_methods_headers_and_query() {
+        let matcher = RouteMatcher::from_config(&RouteMatchConfig {
+            methods: strings(&["GET", "HEAD"]),
+            headers: vec![field("x-version", None, Some(r"^v2(\.|$)")), field("x-canary", None, None)],
+            query: vec![field("format", Some("json"), None)],
+            ..Default::default()
+        });
+        let headers = [("x-version", "v2.1"), ("X-Canary", "")];
+        assert!(matches(&matcher, "GET", "/?a=1&format=json", &headers));
+        assert!(matches(&matcher, "HEAD", "/?format=%6Ason", &headers));
+        assert!(!matches(&matcher, "POST", "/?format=json", &headers));
+        assert!(!matches(&matcher, "GET", "/?format=xml", &headers));
+        assert!(!matches(&matcher, "GET", "/", &headers));
+        assert!(!matches(&matcher, "GET", "/?format=json", &[("x-version", "v20"), ("x-canary", "")]));
+        assert!(!matches(&matcher, "GET", "/?format=json", &[("x-version", "v2")]));
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+
+static REGISTRY: LazyLock<Registry> = LazyLock::new(Registry::default);
+
+/// Gateway metrics, rendered in the Prometheus text format on the admin listener's `/metrics`.
+#[derive(Debug, Default)]
+pub struct Registry {
+    families: Mutex<BTreeMap<&'static str, Family>>,
+}
+
+#[derive(Debug)]
+struct Family {
+    help: &'static str,
+    kind: &'static str,
+    // Keyed by the rendered label set
+    series: BTreeMap<String, Arc<AtomicI64>>,
+}
+
+/// A monotonically increasing count.
+#[derive(Debug, Clone)]
+pub struct Counter(Arc<AtomicI64>);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A value that goes up and down, such as open connections.
+#[derive(Debug, Clone)]
+pub struct Gauge(Arc<AtomicI64>);
+
+impl Gauge {
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Registry {
+    pub fn global() -> &'static Registry {
+        &REGISTRY
+    }
+
+    /// Returns the counter for `name` and `labels`, creating it at zero. Series
+    /// live for the lifetime of the process, so a reload keeps their values.
+    pub fn counter(&self, name: &'static str, help: &'static str, labels: &[(&str, &str)]) -> Counter {
+        Counter(self.series(name, help, "counter", labels))
+    }
+
+    pub fn gauge(&self, name: &'static str, help: &'static str, labels: &[(&str, &str)]) -> Gauge {
+        Gauge(self.series(name, help, "gauge", labels))
+    }
+
+    fn series(&self, name: &'static str, help: &'static str, kind: &'static str, labels: &[(&str, &str)]) -> Arc<AtomicI64> {
+        let mut families = self.families.lock().unwrap();
+        let family = families.entry(name).or_insert_with(|| Family {
+            help,
+            kind,
+            series: BTreeMap::new(),
+        });
+        family.series.entry(render_labels(labels)).or_default().clone()
+    }
+
+    pub fn render(&self) -> String {
+        let families = self.families.lock().unwrap();
+        let mut output = String::new();
+        for (name, family) in families.iter() {
+            let _ = writeln!(output, "# HELP {} {}", name, family.help);
+            let _ = writeln!(output, "# TYPE {} {}", name, family.kind);
+            for (labels, value) in &family.series {
+                let _ = writeln!(output, "{}{} {}", name, labels, value.load(Ordering::Relaxed));
+            }
+        }
+        output
+    }
+}
+
+fn render_labels(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(key, value)| {
+            let value = value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+            format!("{}=\"{}\"", key, value)
+        })
+        .collect();
+    format!("{{{}}}", labels.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let registry = Registry::default();
+        let counter = registry.counter("requests_total", "Requests seen.", &[("service", "/py")]);
+        counter.inc();
+        counter.inc();
+        // The same name and labels share one series
+        registry.counter("requests_total", "Requests seen.", &[("service", "/py")]).inc();
+        registry.counter("requests_total", "Requests seen.", &[("service", "/g\"o")]);
+        let gauge = registry.gauge("connections", "Open connections.", &[]);
+        gauge.inc();
+        gauge.inc();
+        gauge.dec();
+
+        assert_eq!(counter.get(), 3);
+        assert_eq!(
+            registry.render(),
+            "# HELP connections Open connections.\n\
+             # TYPE connections gauge\n\
+             connections 1\n\
+             # HELP requests_total Requests seen.\n\
+             # TYPE requests_total counter\n\
+             requests_total{service=\"/g\\\"o\"} 0\n\
+             requests_total{service=\"/py\"} 3\n"
+        );
+    }
+}
@@ -0,0 +1,238 @@
+use crate::endpoint::Endpoint;
+use leyline_config::OutlierDetectionConfig;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Passive outlier detection for the endpoints of one service, fed with the
+/// outcome of every proxied attempt.
+#[derive(Debug)]
+pub struct OutlierDetector {
+    prefix: String,
+    config: OutlierDetectionConfig,
+    endpoints: Vec<Arc<Endpoint>>,
+    stats: Vec<Mutex<OutlierStats>>,
+}
+
+#[derive(Debug)]
+struct OutlierStats {
+    consecutive_failures: u32,
+    window_started: Instant,
+    window_requests: u32,
+    window_failures: u32,
+    // Ejections in a row, drives the exponential ejection time
+    ejections: u32,
+    ejection_ends: Option<Instant>,
+}
+
+impl OutlierDetector {
+    pub fn new(prefix: impl Into<String>, endpoints: &[Arc<Endpoint>], config: &OutlierDetectionConfig) -> Self {
+        let now = Instant::now();
+        Self {
+            prefix: prefix.into(),
+            config: config.clone(),
+            endpoints: endpoints.to_vec(),
+            stats: endpoints
+                .iter()
+                .map(|_| {
+                    Mutex::new(OutlierStats {
+                        consecutive_failures: 0,
+                        window_started: now,
+                        window_requests: 0,
+                        window_failures: 0,
+                        ejections: 0,
+                        ejection_ends: None,
+                    })
+                })
+                .collect(),
+        }
+    }
+
+    /// Records whether an attempt against `index` succeeded.
+    pub fn record(&self, index: usize, success: bool) {
+        self.record_at(index, success, Instant::now());
+    }
+
+    pub fn record_at(&self, index: usize, success: bool, now: Instant) {
+        self.return_expired(now);
+
+        let endpoint = &self.endpoints[index];
+        let interval = Duration::from_secs(self.config.interval_seconds);
+        let mut stats = self.stats[index].lock().unwrap();
+
+        if now.duration_since(stats.window_started) >= interval {
+            let previous_window = stats.window_started;
+            stats.window_started = now;
+            stats.window_requests = 0;
+            stats.window_failures = 0;
+            // A host that stayed in rotation for a whole interval earns back one ejection step
+            let in_rotation_since = stats.ejection_ends.map_or(previous_window, |end| end.max(previous_window));
+            if now.saturating_duration_since(in_rotation_since) >= interval {
+                stats.ejections = stats.ejections.saturating_sub(1);
+            }
+        }
+
+        stats.window_requests += 1;
+        if success {
+            stats.consecutive_failures = 0;
+            return;
+        }
+        stats.window_failures += 1;
+        stats.consecutive_failures += 1;
+
+        if endpoint.is_ejected_at(now) {
+            return;
+        }
+
+        let reason = if stats.consecutive_failures >= self.config.consecutive_failures {
+            format!("{} consecutive failures", stats.consecutive_failures)
+        } else if stats.window_requests >= self.config.failure_rate_minimum_requests
+            && stats.window_failures * 100 >= self.config.failure_rate_percent * stats.window_requests
+        {
+            format!("{}/{} requests failed", stats.window_failures, stats.window_requests)
+        } else {
+            return;
+        };
+
+        if !self.can_eject(now) {
+            tracing::warn!(
+                "upstream {} of {} is an outlier ({}) but max_ejection_percent {}% is reached, keeping it",
+                endpoint.url, self.prefix, reason, self.config.max_ejection_percent
+            );
+            return;
+        }
+
+        stats.ejections += 1;
+        let duration = self.ejection_duration(stats.ejections);
+        stats.consecutive_failures = 0;
+        stats.window_requests = 0;
+        stats.window_failures = 0;
+        stats.ejection_ends = Some(now + duration);
+        endpoint.eject_until(now + duration);
+
+        tracing::warn!(
+            "ejecting upstream {} of {} for {:?} ({}, ejection #{})",
+            endpoint.url, self.prefix, duration, reason, stats.ejections
+        );
+    }
+
+    fn ejection_duration(&self, ejections: u32) -> Duration {
+        let base = self.config.base_ejection_seconds;
+        let seconds = base.saturating_mul(1u64 << (ejections - 1).min(32));
+        Duration::from_secs(seconds.min(self.config.max_ejection_seconds))
+    }
+
+    fn can_eject(&self, now: Instant) -> bool {
+        let ejected = self.endpoints.iter().filter(|endpoint| endpoint.is_ejected_at(now)).count();
+        (ejected + 1) * 100 <= self.config.max_ejection_percent as usize * self.endpoints.len()
+    }
+
+    fn return_expired(&self, now: Instant) {
+        for endpoint in &self.endpoints {
+            if endpoint.clear_expired_ejection(now) {
+                tracing::info!("upstream {} of {} returned from ejection", endpoint.url, self.prefix);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> OutlierDetectionConfig {
+        OutlierDetectionConfig {
+            consecutive_failures: 3,
+            failure_rate_percent: 50,
+            failure_rate_minimum_requests: 10,
+            interval_seconds: 10,
+            base_ejection_seconds: 30,
+            max_ejection_seconds: 100,
+            max_ejection_percent: 50,
+        }
+    }
+
+    fn endpoints(count: usize) -> Vec<Arc<Endpoint>> {
+        (0..count)
+            .map(|i| Arc::new(Endpoint::new(format!("http://127.0.0.1:{}", 8080 + i), 1)))
+            .collect()
+    }
+
+    fn fail(detector: &OutlierDetector, index: usize, times: usize, now: Instant) {
+        for _ in 0..times {
+            detector.record_at(index, false, now);
+        }
+    }
+
+    #[test]
+    fn test_consecutive_failures_eject() {
+        let endpoints = endpoints(2);
+        let detector = OutlierDetector::new("/py", &endpoints, &config());
+        let now = Instant::now();
+
+        fail(&detector, 0, 2, now);
+        detector.record_at(0, true, now);
+        fail(&detector, 0, 2, now);
+        assert!(!endpoints[0].is_ejected_at(now));
+
+        detector.record_at(0, false, now);
+        assert!(endpoints[0].is_ejected_at(now));
+        assert!(endpoints[0].is_ejected_at(now + Duration::from_secs(29)));
+        assert!(!endpoints[0].is_ejected_at(now + Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn test_ejection_time_grows_exponentially() {
+        let endpoints = endpoints(2);
+        let detector = OutlierDetector::new("/py", &endpoints, &config());
+        let mut now = Instant::now();
+
+        for expected in [30, 60, 100, 100] {
+            fail(&detector, 0, 3, now);
+            assert!(endpoints[0].is_ejected_at(now + Duration::from_secs(expected - 1)));
+            assert!(!endpoints[0].is_ejected_at(now + Duration::from_secs(expected)));
+            // Fail again right after returning, before a clean interval could reset the count
+            now += Duration::from_secs(expected);
+            detector.record_at(1, true, now);
+            assert!(!endpoints[0].is_ejected_at(now));
+        }
+    }
+
+    #[test]
+    fn test_failure_rate_ejects() {
+        let endpoints = endpoints(2);
+        let detector = OutlierDetector::new("/py", &endpoints, &config());
+        let now = Instant::now();
+
+        for i in 0..10 {
+            // Alternate so the consecutive failure threshold never triggers
+            detector.record_at(0, i % 2 == 0, now);
+        }
+        assert!(endpoints[0].is_ejected_at(now));
+    }
+
+    #[test]
+    fn test_max_ejection_percent() {
+        let endpoints = endpoints(2);
+        let detector = OutlierDetector::new("/py", &endpoints, &config());
+        let now = Instant::now();
+
+        fail(&detector, 0, 3, now);
+        fail(&detector, 1, 3, now);
+        assert!(endpoints[0].is_ejected_at(now));
+        assert!(!endpoints[1].is_ejected_at(now));
+    }
+
+    #[test]
+    fn test_clean_interval_resets_ejection_time() {
+        let endpoints = endpoints(2);
+        let detector = OutlierDetector::new("/py", &endpoints, &config());
+        let now = Instant::now();
+
+        fail(&detector, 0, 3, now);
+        // Back in rotation at +30s and healthy for a full interval afterwards
+        let later = now + Duration::from_secs(45);
+        detector.record_at(0, true, later);
+        fail(&detector, 0, 3, later);
+        assert!(!endpoints[0].is_ejected_at(later + Duration::from_secs(30)));
+    }
+}
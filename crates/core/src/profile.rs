@@ -0,0 +1,42 @@
+/// Per-binary behaviour of the shared proxy engine.
+#[derive(Debug, Clone)]
+pub struct Profile {
+    pub name: &'static str,
+    // Extra request headers to drop on top of the hop-by-hop ones
+    pub strip_request_headers: &'static [&'static str],
+    // Replaces the client's User-Agent when set
+    pub user_agent: Option<&'static str>,
+    // Extra response headers to drop on top of the hop-by-hop ones
+    pub strip_response_headers: &'static [&'static str],
+    // Some servers (like Gin) hang up on reused connections
+    pub connection_pooling: bool,
+}
+
+// Hop-by-hop headers (RFC 7230 section 6.1) plus host, which the client sets from the upstream URL
+pub const HOP_BY_HOP_REQUEST_HEADERS: &[&str] = &[
+    "host",
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailers",
+    "transfer-encoding",
+    "upgrade",
+];
+
+pub const HOP_BY_HOP_RESPONSE_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "transfer-encoding",
+];
+
+impl Profile {
+    pub fn skip_request_header(&self, name: &str) -> bool {
+        HOP_BY_HOP_REQUEST_HEADERS.contains(&name) || self.strip_request_headers.contains(&name)
+    }
+
+    pub fn skip_response_header(&self, name: &str) -> bool {
+        HOP_BY_HOP_RESPONSE_HEADERS.contains(&name) || self.strip_response_headers.contains(&name)
+    }
+}
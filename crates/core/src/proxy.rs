@@ -0,0 +1,708 @@
+use crate::balancer::hash::request_hash;
+use crate::balancer::PickContext;
+use crate::body::{RequestBody, ResponseBody};
+use crate::circuit::{CircuitBreaker, CircuitPermit};
+use crate::client::UpstreamClients;
+use crate::endpoint::{Endpoint, InFlightGuard, LatencyTimer};
+use crate::hedge::HedgePolicy;
+use crate::profile::Profile;
+use crate::retry::{parse_retry_after, Failure};
+use crate::rewrite;
+use crate::routing::RouteTable;
+use crate::service::UpstreamService;
+use crate::upgrade::{self, UpgradePolicy};
+use arc_swap::ArcSwap;
+use axum::{
+    body::{Body, Bytes},
+    extract::{ConnectInfo, Request},
+    http::{header, HeaderMap, HeaderValue, Method, Response, StatusCode, Uri},
+    response::IntoResponse,
+};
+use hyper::body::Incoming;
+use hyper_util::rt::TokioIo;
+use leyline_config::{AuthConfig, StreamingConfig};
+use leyline_error::GatewayError;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::Instant;
+
+#[derive(Clone)]
+pub struct ProxyState {
+    pub clients: Arc<UpstreamClients>,
+    // Swapped atomically on reload, in-flight requests keep the table they started with
+    pub routes: Arc<ArcSwap<RouteTable>>,
+    pub auth: Arc<AuthConfig>,
+    pub profile: Arc<Profile>,
+}
+
+pub async fn proxy_handler(
+    axum::extract::State(state): axum::extract::State<ProxyState>,
+    mut req: Request,
+) -> Result<impl IntoResponse, GatewayError> {
+    // Check API key for proxy requests (check against multiple valid keys)
+    if state.auth.enabled {
+        let api_key_header = req.headers().get("x-api-key");
+        if let Some(api_key) = api_key_header {
+            let api_key = api_key.to_str().unwrap_or("");
+            if !state.auth.api_keys.iter().any(|key| key == api_key) {
+                return Ok((StatusCode::UNAUTHORIZED, "Invalid API key").into_response());
+            }
+        } else {
+            return Ok((StatusCode::UNAUTHORIZED, "API key required").into_response());
+        }
+    }
+
+    // Find the service matching the request, see `RouteTable` for the order of routes
+    let routes = state.routes.load_full();
+    let route = routes
+        .lookup(req.method(), req.uri(), req.headers())
+        .ok_or_else(|| GatewayError::NoRoute { method: req.method().to_string(), path: req.uri().path().to_string() })?;
+    let rest = route.rest;
+    if !route.params.is_empty() {
+        tracing::debug!("routed to {} with path parameters {:?}", route.service.prefix, route.params);
+    }
+
+    // Split routes hand the request on to one of their clusters
+    let client_ip = req.extensions().get::<ConnectInfo<SocketAddr>>().map(|info| info.0.ip());
+    let upstream_service = match &route.service.split {
+        Some(split) => {
+            let cluster = split.choose(req.headers(), req.uri(), client_ip);
+            tracing::debug!("{} sends the request to cluster {}", route.service.prefix, cluster.name);
+            &cluster.service
+        }
+        None => route.service,
+    };
+
+    // Methods outside the service's allow-list never reach an upstream
+    if let Some(allowed) = &upstream_service.allowed_methods
+        && !allowed.contains(req.method())
+    {
+        tracing::debug!("method {} is not allowed for {}", req.method(), upstream_service.prefix);
+        return Err(GatewayError::MethodNotAllowed {
+            method: req.method().to_string(),
+            allowed: allowed.iter().map(Method::to_string).collect(),
+        });
+    }
+
+    // Fail fast while the whole service is known to be down
+    let service_permit = match upstream_service.circuit_breaker.as_deref().map(CircuitBreaker::try_acquire).transpose() {
+        Ok(permit) => permit,
+        Err(retry_after) => {
+            tracing::debug!("circuit for {} is open, failing fast", upstream_service.prefix);
+            return Err(GatewayError::CircuitOpen { target: upstream_service.prefix.clone(), retry_after });
+        }
+    };
+
+    // Strip or rewrite the prefix, the path of the upstream URL goes in front of it per attempt
+    let upstream_path = match &upstream_service.rewrite {
+        Some(rewrite) => rewrite.path(rest, &route.params),
+        None => rest.to_string(),
+    };
+    let query = req.uri().query().map(String::from);
+
+    if let Some(budget) = &upstream_service.retry_budget {
+        budget.record_request();
+    }
+
+    let method = req.method().clone();
+
+    // Consistent-hash balancers need the request's hash key
+    let hash = upstream_service.hash_key.as_ref().and_then(|key| request_hash(key, req.headers(), req.uri(), client_ip));
+
+    // Upstream named by a valid affinity cookie, tried first unless it is marked down
+    let sticky_index = upstream_service.sticky_session
+        .as_ref()
+        .and_then(|sticky| sticky.lookup(req.headers(), &upstream_service.endpoints))
+        .filter(|&index| upstream_service.endpoints[index].is_available());
+
+    // Upgrade handshakes such as WebSockets take over the connection instead
+    if let Some(policy) = &upstream_service.upgrade
+        && let Some(protocol) = upgrade::requested_protocol(req.headers())
+        && policy.allows(protocol)
+    {
+        let server_index = sticky_index
+            .or_else(|| upstream_service.pick_for(&PickContext { hash, ..Default::default() }))
+            .ok_or(GatewayError::Internal)?;
+        return proxy_upgrade(&state, upstream_service, policy.clone(), req, (&upstream_path, query.as_deref()), server_index, service_permit).await;
+    }
+
+    // Every attempt has to finish before this
+    let timeouts = upstream_service.timeouts;
+    let deadline = Instant::now() + timeouts.total;
+    let client = state.clients.get(timeouts.connect)?;
+
+    // Read the body once so every attempt sends the same payload
+    let body = std::mem::take(req.body_mut());
+    let mut body = RequestBody::read(req.headers(), body, upstream_service.max_replay_body_bytes)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to read request body: {}", e);
+            GatewayError::Internal
+        })?;
+    let max_attempts = if body.is_replayable() {
+        upstream_service.max_retries
+    } else {
+        tracing::debug!("request body exceeds {} bytes, it will not be retried", upstream_service.max_replay_body_bytes);
+        upstream_service.max_retries.min(1)
+    };
+
+    let hedge_delay = upstream_service.hedge
+        .as_ref()
+        .filter(|_| HedgePolicy::applies_to(req.method()) && body.is_replayable())
+        .map(|hedge| hedge.delay());
+
+    // Try each upstream server with retry logic
+    let mut last_error = None;
+    // Shortest wait among upstreams skipped because their circuit is open
+    let mut circuit_open: Option<Duration> = None;
+    // Error status response of the latest attempt
+    let mut error_response = None;
+    let mut tried = Vec::with_capacity(upstream_service.max_retries);
+
+    for attempt in 0..max_attempts {
+        if Instant::now() >= deadline {
+            last_error = Some(GatewayError::DeadlineExceeded);
+            break;
+        }
+
+        // Ask the balancer for a server that hasn't been tried for this request yet,
+        // unless the affinity cookie names one that hasn't failed
+        let sticky_candidate = sticky_index.filter(|index| !tried.contains(index));
+        let Some(server_index) = sticky_candidate.or_else(|| upstream_service.pick_for(&PickContext { tried: &tried, hash, ..Default::default() })) else {
+            break;
+        };
+        tried.push(server_index);
+        let endpoint = &upstream_service.endpoints[server_index];
+        let upstream_url = endpoint.url.as_str();
+        let upstream_uri = rewrite::upstream_uri(upstream_url, &upstream_path, query.as_deref())?;
+
+        let endpoint_permit = match endpoint.circuit_breaker().map(CircuitBreaker::try_acquire).transpose() {
+            Ok(permit) => permit,
+            Err(retry_after) => {
+                tracing::debug!("circuit for upstream server {} is open, skipping it", upstream_url);
+                circuit_open = Some(circuit_open.map_or(retry_after, |wait| wait.min(retry_after)));
+                continue;
+            }
+        };
+
+        tracing::debug!("attempting request to upstream server: {} (attempt {}/{})",
+                       upstream_url, attempt + 1, max_attempts);
+
+        let Some(request) = build_request(&method, upstream_uri, req.headers(), upstream_service, &state.profile, &mut body) else {
+            // A streamed body can't be sent twice
+            break;
+        };
+
+        // Tracks in-flight requests and latency for the balancer
+        let leg = Leg {
+            server_index,
+            endpoint,
+            permit: endpoint_permit,
+            started: Instant::now(),
+            latency: endpoint.start_timer(),
+            in_flight: endpoint.start_request(),
+        };
+        let first_byte_timeout = || GatewayError::FirstByteTimeout(upstream_url.to_string());
+        let send = within(client.request(request), timeouts.first_byte, deadline, first_byte_timeout);
+
+        // Safe requests get a parallel attempt on another upstream when this one is slow
+        let (sent, leg, failed_leg) = match hedge_delay {
+            Some(delay) => {
+                let headers = req.headers();
+                let start_hedge = || {
+                    // Only healthy upstreams are worth hedging to
+                    let hedge_index = upstream_service.load_balancer
+                        .pick(&upstream_service.endpoints, &PickContext { tried: &tried, hash, ..Default::default() })?;
+                    let hedge_endpoint = &upstream_service.endpoints[hedge_index];
+                    let permit = hedge_endpoint.circuit_breaker().map(CircuitBreaker::try_acquire).transpose().ok()?;
+                    if let Some(budget) = &upstream_service.retry_budget
+                        && !budget.try_hedge()
+                    {
+                        return None;
+                    }
+                    let hedge_uri = rewrite::upstream_uri(&hedge_endpoint.url, &upstream_path, query.as_deref()).ok()?;
+                    let request = build_request(&method, hedge_uri, headers, upstream_service, &state.profile, &mut body)?;
+                    tried.push(hedge_index);
+
+                    tracing::debug!("no response from {} after {:?}, hedging with {}", upstream_url, delay, hedge_endpoint.url);
+                    let hedge_url = hedge_endpoint.url.clone();
+                    let leg = Leg {
+                        server_index: hedge_index,
+                        endpoint: hedge_endpoint,
+                        permit,
+                        started: Instant::now(),
+                        latency: hedge_endpoint.start_timer(),
+                        in_flight: hedge_endpoint.start_request(),
+                    };
+                    let send = within(client.request(request), timeouts.first_byte, deadline, move || GatewayError::FirstByteTimeout(hedge_url));
+                    Some((leg, send))
+                };
+                race(send, leg, delay, start_hedge).await
+            }
+            None => (send.await, leg, None),
+        };
+        if let Some(failed) = failed_leg {
+            upstream_service.record_outcome(failed.server_index, false);
+            if let Some(permit) = failed.permit {
+                permit.record(false);
+            }
+        }
+        let Leg { server_index, endpoint, permit: endpoint_permit, started, latency, in_flight } = leg;
+        // The response headers are in, or the attempt failed; a streamed body
+        // says more about the client than about the upstream
+        drop(latency);
+        let upstream_url = endpoint.url.as_str();
+        if let (Some(hedge), Ok(Ok(_))) = (&upstream_service.hedge, &sent) {
+            hedge.record_latency(started.elapsed());
+        }
+
+        // Upstream's Retry-After on a retryable error status
+        let mut retry_after = None;
+        let failure = match sent {
+            Ok(Ok(response)) => {
+                let status = response.status();
+
+                // For successful responses, forward everything back
+                if status.is_success() || status.is_redirection() || status.is_informational() || status.is_client_error() {
+                    tracing::debug!("successful response from: {} with status: {}", upstream_url, status);
+
+                    // The body is streamed after this returns, so the upstream is
+                    // judged by its response headers
+                    let mut response = forward_response(response, &state.profile, upstream_service, deadline, upstream_url, Some(in_flight));
+                    upstream_service.record_outcome(server_index, true);
+                    if let Some(permit) = endpoint_permit {
+                        permit.record(true);
+                    }
+
+                    // (Re-)issue the affinity cookie when the request didn't land on its upstream
+                    if let Some(sticky) = &upstream_service.sticky_session
+                        && sticky_index != Some(server_index)
+                    {
+                        response.headers_mut().append(axum::http::header::SET_COOKIE, sticky.set_cookie(endpoint));
+                    }
+
+                    if let Some(permit) = service_permit {
+                        permit.record(true);
+                    }
+                    return Ok(response);
+                } else {
+                    // Server errors - try next server if the retry policy allows it
+                    tracing::warn!("upstream server {} returned server error status: {}", upstream_url, status);
+                    upstream_service.record_outcome(server_index, false);
+                    if let Some(permit) = endpoint_permit {
+                        permit.record(false);
+                    }
+                    retry_after = response.headers()
+                        .get("retry-after")
+                        .and_then(|value| parse_retry_after(value.as_bytes()));
+                    last_error = Some(GatewayError::UpstreamStatus(status.as_u16()));
+                    // Kept unread in case this turns out to be the last attempt
+                    error_response = Some((response, upstream_url));
+                    Failure::Status(status.as_u16())
+                }
+            }
+            Ok(Err(e)) => {
+                error_response = None;
+                upstream_service.record_outcome(server_index, false);
+                if let Some(permit) = endpoint_permit {
+                    permit.record(false);
+                }
+
+                // Check if it's a timeout or network error
+                if e.is_connect() && is_timeout(&e) {
+                    tracing::warn!("connecting to upstream server {} timed out after {:?}", upstream_url, timeouts.connect);
+                    last_error = Some(GatewayError::ConnectTimeout(upstream_url.to_string()));
+                    Failure::ConnectFailure
+                } else if e.is_connect() {
+                    tracing::warn!("failed to connect to upstream server {}: {:?}", upstream_url, e);
+                    last_error = Some(GatewayError::HttpRequest(e));
+                    Failure::ConnectFailure
+                } else {
+                    // The request may have reached the upstream before the connection broke
+                    tracing::warn!("request to upstream server {} failed: {:?}", upstream_url, e);
+                    last_error = Some(GatewayError::HttpRequest(e));
+                    Failure::Reset
+                }
+            }
+            Err(error) => {
+                error_response = None;
+                upstream_service.record_outcome(server_index, false);
+                if let Some(permit) = endpoint_permit {
+                    permit.record(false);
+                }
+                tracing::warn!("request to upstream server {} failed: {}", upstream_url, error);
+                last_error = Some(error);
+                Failure::Timeout
+            }
+        };
+
+        // Out of time, further attempts would fail the same way
+        if matches!(last_error, Some(GatewayError::DeadlineExceeded)) {
+            break;
+        }
+
+        if !upstream_service.retry_policy.should_retry(req.method(), failure) {
+            tracing::debug!("retry policy of {} doesn't retry {:?} for {}", upstream_service.prefix, failure, req.method());
+            break;
+        }
+
+        // If this is not the last attempt, back off before trying the next server
+        if attempt + 1 < max_attempts {
+            if let Some(budget) = &upstream_service.retry_budget
+                && !budget.try_retry()
+            {
+                tracing::warn!("retry budget of {} is exhausted, not retrying", upstream_service.prefix);
+                break;
+            }
+            let delay = upstream_service.retry_policy.backoff(attempt as u32 + 1, retry_after);
+            if delay >= deadline.saturating_duration_since(Instant::now()) {
+                tracing::warn!("not retrying, backing off for {:?} would pass the request deadline", delay);
+                break;
+            }
+            tracing::info!("retrying with next upstream server in {:?}...", delay);
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    // Only requests that actually reached an upstream count against the service circuit
+    if let Some(error) = last_error {
+        tracing::error!("all upstream servers failed after {} attempts", tried.len());
+        if let Some(permit) = service_permit {
+            permit.record(false);
+        }
+        // Pass the upstream's own error response through instead of a gateway error
+        if upstream_service.forward_upstream_errors
+            && let Some((response, upstream_url)) = error_response
+        {
+            return Ok(forward_response(response, &state.profile, upstream_service, deadline, upstream_url, None));
+        }
+        return Err(error);
+    }
+
+    match circuit_open {
+        Some(retry_after) => Err(GatewayError::CircuitOpen { target: upstream_service.prefix.clone(), retry_after }),
+        None => Err(GatewayError::Internal),
+    }
+}
+
+/// Forwards an `Upgrade` handshake to one upstream and, once it switches
+/// protocols, splices the client and upstream connections together.
+async fn proxy_upgrade(
+    state: &ProxyState,
+    upstream_service: &UpstreamService,
+    policy: Arc<UpgradePolicy>,
+    mut req: Request,
+    (upstream_path, query): (&str, Option<&str>),
+    server_index: usize,
+    service_permit: Option<CircuitPermit<'_>>,
+) -> Result<axum::response::Response, GatewayError> {
+    let endpoint = &upstream_service.endpoints[server_index];
+    let upstream_url = endpoint.url.as_str();
+    let endpoint_permit = match endpoint.circuit_breaker().map(CircuitBreaker::try_acquire).transpose() {
+        Ok(permit) => permit,
+        Err(retry_after) => return Err(GatewayError::CircuitOpen { target: upstream_url.to_string(), retry_after }),
+    };
+
+    let timeouts = upstream_service.timeouts;
+    let deadline = Instant::now() + timeouts.total;
+    let client = state.clients.get(timeouts.connect)?;
+    let upstream_uri = rewrite::upstream_uri(upstream_url, upstream_path, query)?;
+
+    // The client's side of the connection, available once the 101 is sent
+    let client_upgrade = hyper::upgrade::on(&mut req);
+
+    // Handshakes carry no body
+    let mut request = build_request(req.method(), upstream_uri, req.headers(), upstream_service, &state.profile, &mut RequestBody::Buffered(Bytes::new()))
+        .ok_or(GatewayError::Internal)?;
+    // The hop-by-hop headers dropped above are the handshake itself
+    request.headers_mut().insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+    if let Some(protocol) = req.headers().get(header::UPGRADE) {
+        request.headers_mut().insert(header::UPGRADE, protocol.clone());
+    }
+
+    tracing::debug!("forwarding upgrade handshake to upstream server {}", upstream_url);
+    let in_flight = endpoint.start_request();
+    let latency = endpoint.start_timer();
+    let first_byte_timeout = || GatewayError::FirstByteTimeout(upstream_url.to_string());
+    let sent = within(client.request(request), timeouts.first_byte, deadline, first_byte_timeout)
+        .await
+        .and_then(|sent| {
+            sent.map_err(|e| match e.is_connect() && is_timeout(&e) {
+                true => GatewayError::ConnectTimeout(upstream_url.to_string()),
+                false => GatewayError::HttpRequest(e),
+            })
+        });
+    drop(latency);
+    drop(in_flight);
+
+    let success = sent.as_ref().is_ok_and(|response| !response.status().is_server_error());
+    upstream_service.record_outcome(server_index, success);
+    if let Some(permit) = endpoint_permit {
+        permit.record(success);
+    }
+    if let Some(permit) = service_permit {
+        permit.record(success);
+    }
+    let mut response = sent.inspect_err(|error| {
+        tracing::warn!("upgrade handshake with upstream server {} failed: {}", upstream_url, error);
+    })?;
+
+    if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+        // Turned down by the upstream, its answer goes back as a regular response
+        tracing::debug!("upstream server {} answered the upgrade with {}", upstream_url, response.status());
+        return Ok(forward_response(response, &state.profile, upstream_service, deadline, upstream_url, None));
+    }
+
+    let upstream_upgrade = hyper::upgrade::on(&mut response);
+    let upstream_url = upstream_url.to_string();
+    tokio::spawn(async move {
+        let (client, upstream) = match tokio::try_join!(client_upgrade, upstream_upgrade) {
+            Ok(connections) => connections,
+            Err(e) => {
+                tracing::warn!("upgrading the connection to {} failed: {}", upstream_url, e);
+                return;
+            }
+        };
+        let _open = policy.open_connection();
+        match upgrade::splice(TokioIo::new(client), TokioIo::new(upstream), policy.idle_timeout).await {
+            Ok((sent, received)) => tracing::debug!(
+                "upgraded connection to {} closed after sending {} and receiving {} bytes",
+                upstream_url, sent, received
+            ),
+            Err(e) => tracing::debug!("upgraded connection to {} closed: {}", upstream_url, e),
+        }
+    });
+
+    // Every header of the 101 belongs to the handshake, hop-by-hop ones included
+    let (parts, _) = response.into_parts();
+    Ok(axum::response::Response::from_parts(parts, Body::empty()))
+}
+
+/// An attempt in flight against one upstream.
+struct Leg<'a> {
+    server_index: usize,
+    endpoint: &'a Arc<Endpoint>,
+    permit: Option<CircuitPermit<'a>>,
+    started: Instant,
+    // Recorded once the response headers arrive, the request stays in flight
+    // until its body has been sent
+    latency: LatencyTimer,
+    in_flight: InFlightGuard,
+}
+
+type SendResult = Result<Result<Response<Incoming>, hyper_util::client::legacy::Error>, GatewayError>;
+
+/// Waits `delay` for the primary attempt, then starts a hedged one and returns
+/// whichever gets a response first along with its leg. The other attempt is
+/// cancelled by dropping it; if it had already failed, its leg is returned so
+/// the failure can be recorded.
+async fn race<'a, P, H, F>(primary: P, primary_leg: Leg<'a>, delay: Duration, start_hedge: H) -> (SendResult, Leg<'a>, Option<Leg<'a>>)
+where
+    P: Future<Output = SendResult>,
+    H: FnOnce() -> Option<(Leg<'a>, F)>,
+    F: Future<Output = SendResult>,
+{
+    tokio::pin!(primary);
+    tokio::select! {
+        result = &mut primary => return (result, primary_leg, None),
+        _ = tokio::time::sleep(delay) => {}
+    }
+
+    let Some((hedge_leg, hedge)) = start_hedge() else {
+        return (primary.await, primary_leg, None);
+    };
+    tokio::pin!(hedge);
+    tokio::select! {
+        result = &mut primary => match result {
+            Ok(Ok(_)) => (result, primary_leg, None),
+            _ => (hedge.await, hedge_leg, Some(primary_leg)),
+        },
+        result = &mut hedge => match result {
+            Ok(Ok(_)) => (result, hedge_leg, None),
+            _ => (primary.await, primary_leg, Some(hedge_leg)),
+        },
+    }
+}
+
+/// Builds the upstream request with the same method, headers and body as the
+/// original, `None` once a streamed body has already been sent.
+fn build_request(
+    method: &Method,
+    upstream_uri: Uri,
+    headers: &HeaderMap,
+    upstream_service: &UpstreamService,
+    profile: &Profile,
+    body: &mut RequestBody,
+) -> Option<axum::http::Request<Body>> {
+    // Forward the request body, buffered bodies are sent again on every attempt
+    let forward_body = !matches!(*method, Method::GET | Method::HEAD);
+    let mut request = axum::http::Request::new(if forward_body { body.take()? } else { Body::empty() });
+    *request.method_mut() = method.clone();
+    *request.uri_mut() = upstream_uri;
+
+    // Forward all headers (except problematic ones that can cause socket hang up)
+    for (key, value) in headers.iter() {
+        if !profile.skip_request_header(key.as_str())
+            // Buffered bodies get their Content-Length set below
+            && (key != header::CONTENT_LENGTH || !body.is_replayable())
+        {
+            request.headers_mut().append(key, value.clone());
+        }
+    }
+
+    // Without a rewrite the client sets Host from the upstream URL
+    if let Some(host) = upstream_service.rewrite.as_ref().and_then(|rewrite| rewrite.host.as_ref()) {
+        request.headers_mut().insert(header::HOST, host.clone());
+    }
+
+    // Set a standard User-Agent to avoid issues with some servers
+    if let Some(user_agent) = profile.user_agent {
+        request.headers_mut().insert(header::USER_AGENT, HeaderValue::from_static(user_agent));
+    }
+
+    // Set Content-Length header explicitly to avoid socket hang up issues
+    if forward_body && let Some(body_len) = body.buffered_len() {
+        request.headers_mut().insert(header::CONTENT_LENGTH, HeaderValue::from(body_len));
+    }
+
+    Some(request)
+}
+
+/// Builds the response for the client, streaming the upstream body through and
+/// cutting it off if the upstream stalls between chunks. Long-lived streams
+/// aren't bound by the request deadline, only by the service's stream idle timeout.
+fn forward_response(
+    response: Response<Incoming>,
+    profile: &Profile,
+    upstream_service: &UpstreamService,
+    deadline: Instant,
+    upstream_url: &str,
+    in_flight: Option<InFlightGuard>,
+) -> axum::response::Response {
+    let (mut parts, body) = response.into_parts();
+    let (idle, deadline) = if is_stream(&parts.headers, &upstream_service.streaming) {
+        tracing::debug!("streaming response from upstream server {}", upstream_url);
+        (Some(Duration::from_secs(upstream_service.streaming.idle_timeout_seconds)), None)
+    } else {
+        (upstream_service.timeouts.idle_body, Some(deadline))
+    };
+
+    // Forward response headers (skip problematic ones)
+    let headers = std::mem::take(&mut parts.headers);
+    let mut name = None;
+    for (key, value) in headers {
+        // Only the first value of a repeated header carries its name
+        if key.is_some() {
+            name = key;
+        }
+        if let Some(name) = &name
+            && !profile.skip_response_header(name.as_str())
+        {
+            parts.headers.append(name.clone(), value);
+        }
+    }
+
+    let body = ResponseBody::new(body, upstream_url, idle, deadline, in_flight);
+    axum::response::Response::from_parts(parts, Body::new(body))
+}
+
+/// Whether a response is a long-lived stream, judged by its media type or, when
+/// configured, by being chunked with no length given.
+fn is_stream(headers: &HeaderMap, streaming: &StreamingConfig) -> bool {
+    let media_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(str::trim);
+    if let Some(media_type) = media_type
+        && streaming.content_types.iter().any(|stream_type| stream_type.eq_ignore_ascii_case(media_type))
+    {
+        return true;
+    }
+
+    streaming.chunked
+        && !headers.contains_key(header::CONTENT_LENGTH)
+        && headers
+            .get_all(header::TRANSFER_ENCODING)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .any(|value| value.to_ascii_lowercase().contains("chunked"))
+}
+
+/// Whether a client error was caused by a timeout, such as the connect timeout.
+fn is_timeout(error: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(error);
+    while let Some(error) = source {
+        if error.downcast_ref::<std::io::Error>().is_some_and(|e| e.kind() == std::io::ErrorKind::TimedOut) {
+            return true;
+        }
+        source = error.source();
+    }
+    false
+}
+
+/// Runs `future` until the phase timeout or the request deadline, whichever comes first.
+async fn within<T>(
+    future: impl Future<Output = T>,
+    phase: Option<Duration>,
+    deadline: Instant,
+    phase_error: impl FnOnce() -> GatewayError,
+) -> Result<T, GatewayError> {
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    match phase.filter(|phase| *phase < remaining) {
+        Some(phase) => tokio::time::timeout(phase, future).await.map_err(|_| phase_error()),
+        None => tokio::time::timeout(remaining, future)
+            .await
+            .map_err(|_| GatewayError::DeadlineExceeded),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slow(delay: Duration) -> impl Future<Output = ()> {
+        tokio::time::sleep(delay)
+    }
+
+    #[test]
+    fn test_is_stream() {
+        let streaming = StreamingConfig::default();
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, "text/event-stream; charset=utf-8".parse().unwrap());
+        assert!(is_stream(&headers, &streaming));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, "application/json".parse().unwrap());
+        headers.insert(header::TRANSFER_ENCODING, "chunked".parse().unwrap());
+        assert!(!is_stream(&headers, &streaming));
+        // Long polling is opted into per service
+        assert!(is_stream(&headers, &StreamingConfig { chunked: true, ..streaming }));
+    }
+
+    #[tokio::test]
+    async fn test_within_phase_timeout() {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let phase_error = || GatewayError::FirstByteTimeout("http://127.0.0.1:8081".to_string());
+
+        let result = within(slow(Duration::from_secs(5)), Some(Duration::from_millis(20)), deadline, phase_error).await;
+        assert!(matches!(result, Err(GatewayError::FirstByteTimeout(_))));
+
+        let result = within(slow(Duration::from_millis(1)), Some(Duration::from_secs(1)), deadline, phase_error).await;
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_within_deadline() {
+        let deadline = Instant::now() + Duration::from_millis(20);
+        let phase_error = || GatewayError::FirstByteTimeout("http://127.0.0.1:8081".to_string());
+
+        // The deadline wins over a longer or missing phase timeout
+        let result = within(slow(Duration::from_secs(5)), Some(Duration::from_secs(1)), deadline, phase_error).await;
+        assert!(matches!(result, Err(GatewayError::DeadlineExceeded)));
+        let result = within(slow(Duration::from_secs(5)), None, deadline, phase_error).await;
+        assert!(matches!(result, Err(GatewayError::DeadlineExceeded)));
+    }
+}
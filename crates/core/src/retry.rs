@@ -0,0 +1,245 @@
+use crate::metrics::{Counter, Registry};
+use axum::http::Method;
+use leyline_config::{RetryBudgetConfig, RetryOn, RetryPolicyConfig};
+use rand::Rng;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Longer Retry-After values are cut down to this, the request deadline ends
+// most requests well before it
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(3600);
+
+/// Why an attempt failed, as far as retrying is concerned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    ConnectFailure,
+    Reset,
+    Timeout,
+    Status(u16),
+}
+
+/// Decides whether a failed attempt is retried and how long to back off first.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    config: RetryPolicyConfig,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::new(&RetryPolicyConfig::default())
+    }
+}
+
+impl RetryPolicy {
+    pub fn new(config: &RetryPolicyConfig) -> Self {
+        Self { config: config.clone() }
+    }
+
+    pub fn should_retry(&self, method: &Method, failure: Failure) -> bool {
+        let enabled = match failure {
+            Failure::ConnectFailure => self.config.retry_on.contains(&RetryOn::ConnectFailure),
+            Failure::Reset => self.config.retry_on.contains(&RetryOn::Reset),
+            Failure::Timeout => self.config.retry_on.contains(&RetryOn::Timeout),
+            Failure::Status(status) => self.config.status_codes.contains(&status),
+        };
+        // A request that never reached the upstream is safe to send again whatever its method
+        enabled && (failure == Failure::ConnectFailure || is_idempotent(method) || self.config.retry_non_idempotent)
+    }
+
+    /// Delay before retry number `retry` (starting at 1), at least the
+    /// upstream's `retry_after` (up to an hour) when the policy honors it.
+    pub fn backoff(&self, retry: u32, retry_after: Option<Duration>) -> Duration {
+        let ceiling = self.config.backoff_base_ms
+            .saturating_mul(1u64 << (retry.saturating_sub(1)).min(32))
+            .min(self.config.backoff_max_ms);
+        let backoff = Duration::from_millis(rand::rng().random_range(0..=ceiling));
+        match retry_after {
+            Some(retry_after) if self.config.honor_retry_after => backoff.max(retry_after.min(MAX_RETRY_AFTER)),
+            _ => backoff,
+        }
+    }
+}
+
+/// Sliding-window retry budget for one service.
+#[derive(Debug)]
+pub struct RetryBudget {
+    config: RetryBudgetConfig,
+    started: Instant,
+    // One bucket per second of the window, oldest first
+    buckets: Mutex<VecDeque<Bucket>>,
+    exhausted: Counter,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    second: u64,
+    requests: u64,
+    retries: u64,
+}
+
+impl RetryBudget {
+    pub fn new(prefix: &str, config: &RetryBudgetConfig) -> Self {
+        Self {
+            config: config.clone(),
+            started: Instant::now(),
+            buckets: Mutex::new(VecDeque::new()),
+            exhausted: Registry::global().counter(
+                "leyline_retry_budget_exhausted_total",
+                "Requests that failed without retrying because the retry budget was used up.",
+                &[("service", prefix)],
+            ),
+        }
+    }
+
+    pub fn record_request(&self) {
+        self.record_request_at(Instant::now());
+    }
+
+    pub fn record_request_at(&self, now: Instant) {
+        let mut buckets = self.buckets.lock().unwrap();
+        self.bucket(&mut buckets, now).requests += 1;
+    }
+
+    /// Takes a retry out of the budget, false when it is used up.
+    pub fn try_retry(&self) -> bool {
+        self.try_retry_at(Instant::now())
+    }
+
+    pub fn try_retry_at(&self, now: Instant) -> bool {
+        let allowed = self.take_at(now);
+        if !allowed {
+            self.exhausted.inc();
+        }
+        allowed
+    }
+
+    /// Takes a hedged attempt out of the budget. Skipping a hedge doesn't fail
+    /// the request, so it isn't counted as exhausted.
+    pub fn try_hedge(&self) -> bool {
+        self.take_at(Instant::now())
+    }
+
+    fn take_at(&self, now: Instant) -> bool {
+        let mut buckets = self.buckets.lock().unwrap();
+        self.bucket(&mut buckets, now);
+
+        let requests: u64 = buckets.iter().map(|bucket| bucket.requests).sum();
+        let retries: u64 = buckets.iter().map(|bucket| bucket.retries).sum();
+        let allowed = (requests * u64::from(self.config.budget_percent) / 100)
+            .max(u64::from(self.config.min_retries_per_second) * self.config.window_seconds);
+        if retries >= allowed {
+            return false;
+        }
+        if let Some(bucket) = buckets.back_mut() {
+            bucket.retries += 1;
+        }
+        true
+    }
+
+    /// Drops buckets that left the window and returns the current one.
+    fn bucket<'a>(&self, buckets: &'a mut VecDeque<Bucket>, now: Instant) -> &'a mut Bucket {
+        let second = now.saturating_duration_since(self.started).as_secs();
+        while buckets.front().is_some_and(|bucket| bucket.second + self.config.window_seconds <= second) {
+            buckets.pop_front();
+        }
+        if buckets.back().is_none_or(|bucket| bucket.second != second) {
+            buckets.push_back(Bucket { second, requests: 0, retries: 0 });
+        }
+        buckets.back_mut().unwrap()
+    }
+}
+
+fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE | Method::PUT | Method::DELETE
+    )
+}
+
+/// Parses a `Retry-After` value given in seconds, HTTP dates are ignored.
+pub fn parse_retry_after(value: &[u8]) -> Option<Duration> {
+    std::str::from_utf8(value).ok()?.trim().parse().ok().map(Duration::from_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_should_retry() {
+        let policy = RetryPolicy::default();
+
+        assert!(policy.should_retry(&Method::GET, Failure::Timeout));
+        assert!(policy.should_retry(&Method::GET, Failure::Status(503)));
+        assert!(!policy.should_retry(&Method::GET, Failure::Status(500)));
+        // POST is only retried when it never reached the upstream
+        assert!(policy.should_retry(&Method::POST, Failure::ConnectFailure));
+        assert!(!policy.should_retry(&Method::POST, Failure::Reset));
+        assert!(!policy.should_retry(&Method::POST, Failure::Status(503)));
+
+        let policy = RetryPolicy::new(&RetryPolicyConfig {
+            retry_on: vec![RetryOn::Reset],
+            retry_non_idempotent: true,
+            ..RetryPolicyConfig::default()
+        });
+        assert!(policy.should_retry(&Method::POST, Failure::Reset));
+        assert!(!policy.should_retry(&Method::POST, Failure::ConnectFailure));
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy::default();
+
+        for _ in 0..100 {
+            assert!(policy.backoff(1, None) <= Duration::from_millis(25));
+            assert!(policy.backoff(2, None) <= Duration::from_millis(50));
+            assert!(policy.backoff(10, None) <= Duration::from_millis(250));
+        }
+        assert!(policy.backoff(1, Some(Duration::from_secs(2))) >= Duration::from_secs(2));
+        let huge = parse_retry_after(b"18446744073709551615");
+        assert_eq!(policy.backoff(1, huge), MAX_RETRY_AFTER);
+
+        let policy = RetryPolicy::new(&RetryPolicyConfig { honor_retry_after: false, ..RetryPolicyConfig::default() });
+        assert!(policy.backoff(1, Some(Duration::from_secs(2))) <= Duration::from_millis(25));
+    }
+
+    #[test]
+    fn test_retry_budget() {
+        let budget = RetryBudget::new("/budget-test", &RetryBudgetConfig {
+            budget_percent: 20,
+            min_retries_per_second: 1,
+            window_seconds: 10,
+        });
+        let now = Instant::now();
+
+        // The floor allows 10 retries per window whatever the traffic
+        for _ in 0..30 {
+            budget.record_request_at(now);
+        }
+        for _ in 0..10 {
+            assert!(budget.try_retry_at(now));
+        }
+        assert!(!budget.try_retry_at(now));
+
+        // 20% of 200 requests
+        for _ in 0..170 {
+            budget.record_request_at(now);
+        }
+        for _ in 0..30 {
+            assert!(budget.try_retry_at(now));
+        }
+        assert!(!budget.try_retry_at(now));
+        assert_eq!(budget.exhausted.get(), 2);
+
+        // Everything slides out of the window
+        assert!(budget.try_retry_at(now + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        assert_eq!(parse_retry_after(b"3"), Some(Duration::from_secs(3)));
+        assert_eq!(parse_retry_after(b" 120 "), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after(b"Wed, 21 Oct 2015 07:28:00 GMT"), None);
+    }
+}
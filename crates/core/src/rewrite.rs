@@ -0,0 +1,160 @@
+use axum::http::uri::InvalidUri;
+use axum::http::{HeaderValue, Uri};
+use leyline_config::RewriteConfig;
+use regex::Regex;
+
+/// How a service changes the path and Host header of the requests it forwards.
+#[derive(Debug)]
+pub struct Rewrite {
+    prefix: Option<Vec<Piece>>,
+    regex: Option<(Regex, String)>,
+    pub host: Option<HeaderValue>,
+}
+
+/// Part of a prefix template.
+#[derive(Debug, PartialEq)]
+enum Piece {
+    Literal(String),
+    Param(String),
+}
+
+impl Rewrite {
+    /// Builds the rewrite from a validated configuration.
+    pub fn from_config(config: &RewriteConfig) -> Self {
+        let regex = match (&config.regex, &config.replacement) {
+            (Some(regex), Some(replacement)) => Regex::new(regex).ok().map(|regex| (regex, replacement.clone())),
+            _ => None,
+        };
+        Self {
+            prefix: config.prefix.as_deref().map(parse_template),
+            regex,
+            host: config.host.as_deref().and_then(|host| HeaderValue::from_str(host).ok()),
+        }
+    }
+
+    /// The upstream path for a request that left `rest` after the route prefix
+    /// and matched the path parameters `params`.
+    pub fn path(&self, rest: &str, params: &[(String, String)]) -> String {
+        let mut path = match &self.prefix {
+            Some(template) => join_paths(&render(template, params), rest),
+            None => rest.to_string(),
+        };
+        if let Some((regex, replacement)) = &self.regex {
+            path = regex.replace(&path, replacement.as_str()).into_owned();
+        }
+        match path.starts_with('/') || path.is_empty() {
+            true => path,
+            false => format!("/{}", path),
+        }
+    }
+}
+
+/// The URI of the request to an upstream: the path of `upstream_url` as a base
+/// path, followed by `path` and the client's query.
+pub fn upstream_uri(upstream_url: &str, path: &str, query: Option<&str>) -> Result<Uri, InvalidUri> {
+    // The configuration only allows absolute http and https URLs
+    let path_start = upstream_url
+        .find("://")
+        .and_then(|scheme_end| upstream_url[scheme_end + 3..].find('/').map(|start| scheme_end + 3 + start))
+        .unwrap_or(upstream_url.len());
+    let (origin, base_path) = upstream_url.split_at(path_start);
+
+    let mut uri = format!("{}{}", origin, join_paths(base_path, path));
+    if uri.len() == origin.len() {
+        uri.push('/');
+    }
+    if let Some(query) = query {
+        uri.push('?');
+        uri.push_str(query);
+    }
+    uri.parse()
+}
+
+/// `base` followed by `path` with a single slash between them. A trailing
+/// slash on `path` is kept, an empty `path` adds nothing.
+fn join_paths(base: &str, path: &str) -> String {
+    if path.is_empty() {
+        return base.to_string();
+    }
+    format!("{}/{}", base.trim_end_matches('/'), path.trim_start_matches('/'))
+}
+
+fn parse_template(template: &str) -> Vec<Piece> {
+    let mut pieces = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let Some(len) = rest[start..].find('}') else { break };
+        if start > 0 {
+            pieces.push(Piece::Literal(rest[..start].to_string()));
+        }
+        pieces.push(Piece::Param(rest[start + 1..start + len].to_string()));
+        rest = &rest[start + len + 1..];
+    }
+    if !rest.is_empty() {
+        pieces.push(Piece::Literal(rest.to_string()));
+    }
+    pieces
+}
+
+fn render(template: &[Piece], params: &[(String, String)]) -> String {
+    template
+        .iter()
+        .map(|piece| match piece {
+            Piece::Literal(literal) => literal.as_str(),
+            Piece::Param(name) => params
+                .iter()
+                .find(|(param, _)| param == name)
+                .map_or("", |(_, value)| value.as_str()),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewrite(prefix: Option<&str>, regex: Option<(&str, &str)>) -> Rewrite {
+        Rewrite::from_config(&RewriteConfig {
+            prefix: prefix.map(String::from),
+            regex: regex.map(|(regex, _)| regex.to_string()),
+            replacement: regex.map(|(_, replacement)| replacement.to_string()),
+            host: None,
+        })
+    }
+
+    #[test]
+    fn test_upstream_uri_normalization() {
+        let uri = |url, path, query| upstream_uri(url, path, query).unwrap().to_string();
+        assert_eq!(uri("http://a:8080", "", None), "http://a:8080/");
+        assert_eq!(uri("http://a:8080", "/x", Some("q=1&r")), "http://a:8080/x?q=1&r");
+        assert_eq!(uri("http://a:8080/", "/x", None), "http://a:8080/x");
+        assert_eq!(uri("http://a:8080/base", "", Some("q=1")), "http://a:8080/base?q=1");
+        assert_eq!(uri("http://a:8080/base/", "/", None), "http://a:8080/base/");
+        assert_eq!(uri("http://a:8080/base//", "//x/", Some("")), "http://a:8080/base/x/?");
+        // Braces in the path are left alone
+        assert_eq!(uri("https://a/base", "/{}", None), "https://a/base/{}");
+    }
+
+    #[test]
+    fn test_rewrite_prefix_template() {
+        let params = [("id".to_string(), "42".to_string())];
+        let rewrite = rewrite(Some("/v2/accounts/{id}/"), None);
+        assert_eq!(rewrite.path("/orders", &params), "/v2/accounts/42/orders");
+        assert_eq!(rewrite.path("", &params), "/v2/accounts/42/");
+
+        let rewrite = self::rewrite(Some("/u-{id}"), None);
+        assert_eq!(rewrite.path("/", &params), "/u-42/");
+        assert_eq!(rewrite.path("", &params), "/u-42");
+    }
+
+    #[test]
+    fn test_rewrite_regex() {
+        let rewrite = rewrite(Some("/api"), Some((r"^/api/(?P<kind>\w+)/(\d+)$", "/${kind}s/$2")));
+        assert_eq!(rewrite.path("/user/7", &[]), "/users/7");
+        assert_eq!(rewrite.path("/user/x", &[]), "/api/user/x");
+
+        // Replacements without a leading slash still give an absolute path
+        let rewrite = self::rewrite(None, Some(("^/", "")));
+        assert_eq!(rewrite.path("/x", &[]), "/x");
+    }
+}
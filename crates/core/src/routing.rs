@@ -0,0 +1,351 @@
+use crate::metrics::{Counter, Registry};
+use crate::service::UpstreamService;
+use axum::http::{HeaderMap, Method, Uri};
+use std::collections::BTreeMap;
+
+/// The services of the gateway. A request goes to the first service whose
+/// prefix and match conditions it meets, trying services in this order:
+///
+/// 1. Longer prefixes first. Prefixes end on segment boundaries: `/py` matches
+///    `/py` and `/py/app`, but not `/python`.
+/// 2. Where the paths branch, static segments before `{param}` segments.
+/// 3. Among services with the same prefix, higher `match.priority` first.
+/// 4. Configuration order.
+///
+/// Requests no route matches go to the default route, if there is one.
+#[derive(Debug)]
+pub struct RouteTable {
+    services: Vec<UpstreamService>,
+    // Names of the `{param}` segments in each service's prefix
+    params: Vec<Vec<String>>,
+    prefixes: PrefixTree<usize>,
+    default_route: Option<usize>,
+    defaulted: Counter,
+    not_found: Counter,
+}
+
+/// The service a request is routed to.
+#[derive(Debug)]
+pub struct RouteMatch<'t, 'a> {
+    pub service: &'t UpstreamService,
+    // The request path after the prefix
+    pub rest: &'a str,
+    // Path parameters by name, as they appear in the path
+    pub params: Vec<(String, String)>,
+}
+
+impl RouteTable {
+    pub fn new(services: Vec<UpstreamService>) -> Self {
+        let mut order: Vec<usize> = (0..services.len()).collect();
+        order.sort_by_key(|&index| std::cmp::Reverse(services[index].matcher.priority));
+        let mut prefixes = PrefixTree::default();
+        for index in order {
+            prefixes.insert(&services[index].prefix, index);
+        }
+        let params = services
+            .iter()
+            .map(|service| {
+                segments(&service.prefix)
+                    .filter_map(|(segment, _)| param_name(segment))
+                    .map(String::from)
+                    .collect()
+            })
+            .collect();
+        let default_route = services.iter().position(|service| service.default_route);
+        let registry = Registry::global();
+        let unmatched = |result| {
+            registry.counter(
+                "leyline_unmatched_requests_total",
+                "Requests no route matched, by whether the default route took them.",
+                &[("result", result)],
+            )
+        };
+        Self {
+            defaulted: unmatched("default_route"),
+            not_found: unmatched("not_found"),
+            services,
+            params,
+            prefixes,
+            default_route,
+        }
+    }
+
+    pub fn services(&self) -> &[UpstreamService] {
+        &self.services
+    }
+
+    /// The service built for the route with `ServiceConfig::route_key` `key`.
+    pub fn service(&self, key: &str) -> Option<&UpstreamService> {
+        self.services.iter().find(|service| service.route_key == key)
+    }
+
+    /// The service for a request, `None` when no route matches it and there is no default route.
+    pub fn lookup<'a>(&self, method: &Method, uri: &'a Uri, headers: &HeaderMap) -> Option<RouteMatch<'_, 'a>> {
+        let found = self.prefixes
+            .matches(uri.path())
+            .into_iter()
+            .find(|found| self.services[*found.value].matcher.matches(method, uri, headers));
+        let Some(found) = found else {
+            let Some(index) = self.default_route else {
+                self.not_found.inc();
+                return None;
+            };
+            self.defaulted.inc();
+            return Some(RouteMatch { service: &self.services[index], rest: uri.path(), params: Vec::new() });
+        };
+        let index = *found.value;
+        let params = self.params[index]
+            .iter()
+            .cloned()
+            .zip(found.params.into_iter().map(String::from))
+            .collect();
+        Some(RouteMatch { service: &self.services[index], rest: found.rest, params })
+    }
+}
+
+/// A radix tree keyed by path segments. Edges hold runs of static segments so
+/// long prefixes without branches cost a single step, `{param}` segments match
+/// any one segment.
+#[derive(Debug)]
+pub struct PrefixTree<T> {
+    root: Node<T>,
+}
+
+#[derive(Debug)]
+struct Node<T> {
+    // In insertion order
+    values: Vec<T>,
+    // Keyed by the first segment of each edge
+    children: BTreeMap<String, Edge<T>>,
+    param: Option<Box<Node<T>>>,
+}
+
+#[derive(Debug)]
+struct Edge<T> {
+    segments: Vec<String>,
+    node: Node<T>,
+}
+
+/// A prefix matching a path.
+#[derive(Debug, PartialEq)]
+pub struct PrefixMatch<'t, 'a, T> {
+    pub value: &'t T,
+    pub rest: &'a str,
+    // Segments matched by `{param}` segments, in path order
+    pub params: Vec<&'a str>,
+    // Segments in the prefix
+    depth: usize,
+}
+
+impl<T> Default for PrefixTree<T> {
+    fn default() -> Self {
+        Self { root: Node::default() }
+    }
+}
+
+impl<T> Default for Node<T> {
+    fn default() -> Self {
+        Self { values: Vec::new(), children: BTreeMap::new(), param: None }
+    }
+}
+
+impl<T> PrefixTree<T> {
+    /// Adds `prefix`. Empty segments and parameter names are ignored, so `/py`,
+    /// `/py/` and `//py` are the same prefix, as are `/users/{id}` and `/users/{user}`.
+    pub fn insert(&mut self, prefix: &str, value: T) {
+        let segments: Vec<String> = segments(prefix).map(|(segment, _)| segment.to_string()).collect();
+        let mut node = &mut self.root;
+        let mut rest = &segments[..];
+        while let Some(first) = rest.first() {
+            if param_name(first).is_some() {
+                node = node.param.get_or_insert_with(Default::default);
+                rest = &rest[1..];
+                continue;
+            }
+            // A new edge takes all static segments up to the next parameter
+            let run = rest.iter().take_while(|segment| param_name(segment).is_none()).count();
+            let edge = node.children
+                .entry(first.clone())
+                .or_insert_with(|| Edge { segments: rest[..run].to_vec(), node: Node::default() });
+
+            let common = edge.segments.iter().zip(&rest[..run]).take_while(|(a, b)| a == b).count();
+            if common < edge.segments.len() {
+                // Split the edge where the new prefix branches off
+                let tail = edge.segments.split_off(common);
+                let child = std::mem::take(&mut edge.node);
+                edge.node.children.insert(tail[0].clone(), Edge { segments: tail, node: child });
+            }
+            node = &mut edge.node;
+            rest = &rest[common..];
+        }
+        node.values.push(value);
+    }
+
+    /// Every prefix matching `path`, longest first. Where prefixes of the same
+    /// length branch, static segments come before parameters, and values of the
+    /// same prefix are in insertion order.
+    pub fn matches<'a>(&self, path: &'a str) -> Vec<PrefixMatch<'_, 'a, T>> {
+        let segments: Vec<(&str, usize)> = segments(path).collect();
+        let mut found = Vec::new();
+        self.root.collect(path, &segments, 0, 0, &mut Vec::new(), &mut found);
+        // Stable, so the order of the walk breaks ties
+        found.sort_by_key(|found| std::cmp::Reverse(found.depth));
+        found
+    }
+
+    /// The first value of the longest prefix of `path`, and the rest of the path after it.
+    pub fn lookup<'a>(&self, path: &'a str) -> Option<(&T, &'a str)> {
+        let found = self.matches(path).into_iter().next()?;
+        Some((found.value, found.rest))
+    }
+}
+
+impl<T> Node<T> {
+    /// Walks the subtree matching `segments`, the rest of `path` from `end` on.
+    fn collect<'t, 'a>(
+        &'t self,
+        path: &'a str,
+        segments: &[(&'a str, usize)],
+        end: usize,
+        depth: usize,
+        params: &mut Vec<&'a str>,
+        found: &mut Vec<PrefixMatch<'t, 'a, T>>,
+    ) {
+        if let Some(&(first, first_end)) = segments.first() {
+            if let Some(edge) = self.children.get(first)
+                && let Some(matched) = segments.get(..edge.segments.len())
+                && edge.segments.iter().zip(matched).all(|(expected, (segment, _))| expected == segment)
+            {
+                let (_, edge_end) = matched[matched.len() - 1];
+                edge.node.collect(path, &segments[matched.len()..], edge_end, depth + matched.len(), params, found);
+            }
+            if let Some(param) = &self.param {
+                params.push(first);
+                param.collect(path, &segments[1..], first_end, depth + 1, params, found);
+                params.pop();
+            }
+        }
+        for value in &self.values {
+            found.push(PrefixMatch { value, rest: &path[end..], params: params.clone(), depth });
+        }
+    }
+}
+
+/// The name of a `{param}` segment.
+fn param_name(segment: &str) -> Option<&str> {
+    segment.strip_prefix('{')?.strip_suffix('}')
+}
+
+/// Non-empty segments of `path` with the offset where each one ends.
+fn segments(path: &str) -> impl Iterator<Item = (&str, usize)> {
+    let mut start = 0;
+    path.split('/').filter_map(move |segment| {
+        let end = start + segment.len();
+        start = end + 1;
+        (!segment.is_empty()).then_some((segment, end))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matcher::RouteMatcher;
+    use leyline_config::RouteMatchConfig;
+    use std::sync::Arc;
+
+    fn tree(prefixes: &[&'static str]) -> PrefixTree<&'static str> {
+        let mut tree = PrefixTree::default();
+        for prefix in prefixes {
+            tree.insert(prefix, *prefix);
+        }
+        tree
+    }
+
+    #[test]
+    fn test_matches_on_segment_boundaries() {
+        let tree = tree(&["/py"]);
+        assert_eq!(tree.lookup("/py"), Some((&"/py", "")));
+        assert_eq!(tree.lookup("/py/"), Some((&"/py", "/")));
+        assert_eq!(tree.lookup("/py/app?x=1"), Some((&"/py", "/app?x=1")));
+        assert_eq!(tree.lookup("/python/app"), None);
+        assert_eq!(tree.lookup("/pyramid"), None);
+    }
+
+    #[test]
+    fn test_longest_prefix_wins() {
+        // Insertion order doesn't matter, and the edges get split along the way
+        let tree = tree(&["/api/v1/users", "/", "/api", "/api/v1/orders", "/api/v2"]);
+        assert_eq!(tree.lookup("/api/v1/users/7"), Some((&"/api/v1/users", "/7")));
+        assert_eq!(tree.lookup("/api/v1/orders"), Some((&"/api/v1/orders", "")));
+        assert_eq!(tree.lookup("/api/v1/other"), Some((&"/api", "/v1/other")));
+        assert_eq!(tree.lookup("/api/v2/x"), Some((&"/api/v2", "/x")));
+        assert_eq!(tree.lookup("/apiary"), Some((&"/", "/apiary")));
+        assert_eq!(tree.lookup("/"), Some((&"/", "/")));
+    }
+
+    #[test]
+    fn test_equivalent_prefixes_keep_insertion_order() {
+        let tree = tree(&["/py", "/py/"]);
+        let values: Vec<_> = tree.matches("//py//app").into_iter().map(|found| (*found.value, found.rest)).collect();
+        assert_eq!(values, [("/py", "//app"), ("/py/", "//app")]);
+    }
+
+    #[test]
+    fn test_path_parameters() {
+        let tree = tree(&["/users/{id}", "/users/{id}/orders", "/users/admin", "/users/{id}/{item}"]);
+        let matches = |path| -> Vec<_> {
+            tree.matches(path).into_iter().map(|found| (*found.value, found.rest, found.params)).collect()
+        };
+        assert_eq!(
+            matches("/users/7/orders/3"),
+            [
+                ("/users/{id}/orders", "/3", vec!["7"]),
+                ("/users/{id}/{item}", "/3", vec!["7", "orders"]),
+                ("/users/{id}", "/orders/3", vec!["7"]),
+            ]
+        );
+        // Static segments win over parameters at the same depth
+        assert_eq!(matches("/users/admin")[..2], [("/users/admin", "", vec![]), ("/users/{id}", "", vec!["admin"])]);
+        // The longer match wins, even through a parameter
+        assert_eq!(matches("/users/admin/orders")[0], ("/users/{id}/orders", "", vec!["admin"]));
+        assert!(matches("/users").is_empty());
+    }
+
+    #[test]
+    fn test_route_table_order() {
+        // Services are told apart by their upstream
+        let service = |prefix: &str, upstream: &str, methods: &[&str], priority| {
+            let mut service = UpstreamService::with_config(prefix, vec![upstream.to_string()], 1, 0);
+            service.matcher = Arc::new(RouteMatcher::from_config(&RouteMatchConfig {
+                methods: methods.iter().map(|method| method.to_string()).collect(),
+                priority,
+                ..Default::default()
+            }));
+            service
+        };
+        let table = RouteTable::new(vec![
+            service("/api", "http://any", &[], 0),
+            service("/api", "http://post", &["POST"], 1),
+            service("/api/users/{id}", "http://user", &["GET"], 0),
+        ]);
+        let route = |method: &str, uri: &str| {
+            let uri: Uri = uri.parse().unwrap();
+            let route = table.lookup(&method.parse().unwrap(), &uri, &HeaderMap::new())?;
+            Some((route.service.endpoints[0].url.clone(), route.rest.to_string(), route.params))
+        };
+
+        let params = vec![("id".to_string(), "7".to_string())];
+        assert_eq!(route("GET", "/api/users/7/x"), Some(("http://user".to_string(), "/x".to_string(), params)));
+        // Routes whose conditions fail fall through to shorter prefixes, higher priorities first
+        assert_eq!(route("POST", "/api/users/7"), Some(("http://post".to_string(), "/users/7".to_string(), vec![])));
+        assert_eq!(route("DELETE", "/api/users/7"), Some(("http://any".to_string(), "/users/7".to_string(), vec![])));
+        assert_eq!(route("GET", "/other"), None);
+
+        let mut services = table.services;
+        services[2].default_route = true;
+        let table = RouteTable::new(services);
+        let uri: Uri = "/other/x".parse().unwrap();
+        let route = table.lookup(&Method::GET, &uri, &HeaderMap::new()).unwrap();
+        assert_eq!((route.service.endpoints[0].url.as_str(), route.rest), ("http://user", "/other/x"));
+    }
+}
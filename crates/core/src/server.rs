@@ -0,0 +1,135 @@
+use crate::admin;
+use crate::client::UpstreamClients;
+use crate::profile::Profile;
+use crate::proxy::{proxy_handler, ProxyState};
+use crate::routing::RouteTable;
+use crate::service::{build_upstream_services, reload_upstream_services};
+use crate::telemetry;
+use arc_swap::ArcSwap;
+use axum::{http::StatusCode, response::IntoResponse, routing::get, Router};
+use leyline_config::GatewayConfig;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tower_http::trace::TraceLayer;
+
+/// Loads the configuration, starts every listener and serves until one of them fails.
+pub async fn run(profile: Profile, config_path: PathBuf) -> Result<(), Box<dyn std::error::Error>> {
+    let config = match GatewayConfig::from_file(&config_path) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}: failed to load configuration: {}", profile.name, e);
+            std::process::exit(1);
+        }
+    };
+
+    let _guard = telemetry::init(&config.logging);
+
+    // Build the default client up front so a broken TLS setup fails at startup
+    let clients = UpstreamClients::new(&profile);
+    clients.get(None).map_err(|e| {
+        tracing::error!("Failed to create HTTP client: {}", e);
+        std::process::exit(1);
+    })?;
+
+    // Configure upstream services with path prefixes
+    let routes = Arc::new(ArcSwap::from_pointee(RouteTable::new(build_upstream_services(&config))));
+
+    // Reload routes on SIGHUP or when the config file changes
+    let reload_routes = routes.clone();
+    leyline_config::reload::spawn(config_path, config.clone(), move |previous, new_config| {
+        let services = reload_upstream_services(new_config, previous, &reload_routes.load());
+        reload_routes.store(Arc::new(RouteTable::new(services)));
+        Ok(())
+    });
+
+    let admin_state = config.admin.as_ref().map(|admin| admin::AdminState {
+        routes: routes.clone(),
+        api_keys: Arc::new(admin.api_keys.clone()),
+    });
+    let state = ProxyState {
+        clients: Arc::new(clients),
+        routes,
+        auth: Arc::new(config.auth.clone()),
+        profile: Arc::new(profile),
+    };
+
+    let app = router(state);
+
+    // Run our app with hyper on every configured listener
+    let mut servers = Vec::new();
+    for listener_config in &config.listeners {
+        let listener = tokio::net::TcpListener::bind(listener_config.address).await?;
+        tracing::debug!("listening on {}", listener_config.address);
+        let app = app.clone();
+        // Connect info gives the proxy the client IP for hash-based balancing
+        let service = app.into_make_service_with_connect_info::<SocketAddr>();
+        servers.push(tokio::spawn(async move { axum::serve(listener, service).await }));
+    }
+    // Admin endpoints are kept off the proxy listeners
+    if let (Some(admin_config), Some(admin_state)) = (&config.admin, admin_state) {
+        let listener = tokio::net::TcpListener::bind(admin_config.address).await?;
+        tracing::debug!("admin endpoints listening on {}", admin_config.address);
+        let service = admin::router(admin_state).into_make_service();
+        servers.push(tokio::spawn(async move { axum::serve(listener, service).await }));
+    }
+    for server in servers {
+        server.await??;
+    }
+    Ok(())
+}
+
+/// Builds our application with routes and middleware.
+pub fn router(state: ProxyState) -> Router {
+    Router::new()
+        .route("/health", get(health_handler))
+        .route("/ping", get(ping_handler))
+        .fallback(proxy_handler)
+        .with_state(state)
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(|request: &axum::http::Request<_>| {
+                    let method = request.method();
+                    let uri = request.uri();
+                    let version = request.version();
+
+                    tracing::info_span!(
+                        "http_request",
+                        method = %method,
+                        uri = %uri,
+                        version = ?version,
+                        user_agent = ?request.headers().get("user-agent"),
+                    )
+                })
+                .on_request(|request: &axum::http::Request<_>, _span: &tracing::Span| {
+                    tracing::info!(
+                        "started processing request: {} {} {:?}",
+                        request.method(),
+                        request.uri(),
+                        request.version()
+                    );
+                })
+                .on_response(|response: &axum::http::Response<_>, latency: std::time::Duration, _span: &tracing::Span| {
+                    tracing::info!(
+                        "finished processing request: status={}, latency={:?}",
+                        response.status(),
+                        latency
+                    );
+                })
+                .on_failure(|error: tower_http::classify::ServerErrorsFailureClass, latency: std::time::Duration, _span: &tracing::Span| {
+                    tracing::error!(
+                        "request failed: error={:?}, latency={:?}",
+                        error,
+                        latency
+                    );
+                })
+        )
+}
+
+async fn health_handler() -> impl IntoResponse {
+    (StatusCode::OK, "OK")
+}
+
+async fn ping_handler() -> impl IntoResponse {
+    (StatusCode::OK, "pong")
+}
@@ -0,0 +1,436 @@
+use crate::balancer::{self, LoadBalancer, PickContext, RoundRobin};
+use crate::circuit::CircuitBreaker;
+use crate::endpoint::Endpoint;
+use crate::health::HealthChecker;
+use crate::hedge::HedgePolicy;
+use crate::matcher::RouteMatcher;
+use crate::outlier::OutlierDetector;
+use crate::retry::{RetryBudget, RetryPolicy};
+use crate::rewrite::Rewrite;
+use crate::routing::RouteTable;
+use crate::split::TrafficSplit;
+use crate::sticky::StickySession;
+use crate::upgrade::UpgradePolicy;
+use leyline_config::{ClusterConfig, GatewayConfig, HashKey, ServiceConfig, StreamingConfig, TimeoutConfig, DEFAULT_MAX_REPLAY_BODY_BYTES};
+use axum::http::Method;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+pub struct UpstreamService {
+    pub prefix: String,
+    // `ServiceConfig::route_key` of the configuration the service was built from
+    pub route_key: String,
+    // Conditions besides the path, see `RouteTable` for the order they are evaluated in
+    pub matcher: Arc<RouteMatcher>,
+    pub default_route: bool,
+    // Requests go to one of these clusters instead of the service's own endpoints, which are empty
+    pub split: Option<Arc<TrafficSplit>>,
+    // Any method is forwarded when unset
+    pub allowed_methods: Option<Vec<Method>>,
+    pub endpoints: Vec<Arc<Endpoint>>,
+    pub load_balancer: Arc<dyn LoadBalancer>,
+    pub hash_key: Option<HashKey>,
+    pub sticky_session: Option<StickySession>,
+    // Keeps the background probes running for as long as the service is in use
+    pub health_checker: Option<Arc<HealthChecker>>,
+    pub outlier_detector: Option<Arc<OutlierDetector>>,
+    // Trips when requests to the service as a whole keep failing
+    pub circuit_breaker: Option<Arc<CircuitBreaker>>,
+    pub timeouts: Timeouts,
+    pub max_retries: usize,
+    pub max_replay_body_bytes: usize,
+    pub retry_policy: RetryPolicy,
+    pub retry_budget: Option<Arc<RetryBudget>>,
+    pub hedge: Option<Arc<HedgePolicy>>,
+    // Responses exempt from the request deadline
+    pub streaming: StreamingConfig,
+    pub upgrade: Option<Arc<UpgradePolicy>>,
+    // The prefix is stripped when unset
+    pub rewrite: Option<Arc<Rewrite>>,
+    pub forward_upstream_errors: bool,
+}
+
+/// Resolved deadlines for requests to a service, `None` phases are only bounded by `total`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    pub connect: Option<Duration>,
+    pub first_byte: Option<Duration>,
+    pub idle_body: Option<Duration>,
+    // Covers every attempt of a request
+    pub total: Duration,
+}
+
+impl Timeouts {
+    pub fn new(total: Duration) -> Self {
+        Self {
+            connect: None,
+            first_byte: None,
+            idle_body: None,
+            total,
+        }
+    }
+
+    pub fn from_config(timeout_seconds: u64, config: &TimeoutConfig) -> Self {
+        Self {
+            connect: config.connect_seconds.map(Duration::from_secs),
+            first_byte: config.first_byte_seconds.map(Duration::from_secs),
+            idle_body: config.idle_body_seconds.map(Duration::from_secs),
+            total: Duration::from_secs(timeout_seconds),
+        }
+    }
+}
+
+impl UpstreamService {
+    /// Builds the service, filling timeouts it leaves unset from `default_timeouts`.
+    pub fn from_config(config: &ServiceConfig, default_timeouts: &TimeoutConfig) -> Self {
+        Self::from_config_reusing(config, default_timeouts, &[])
+    }
+
+    /// Like `from_config`, but upstreams with the URL and weight of one of
+    /// `previous` take over that endpoint and its state.
+    pub fn from_config_reusing(config: &ServiceConfig, default_timeouts: &TimeoutConfig, previous: &[Arc<Endpoint>]) -> Self {
+        let mut previous = previous.to_vec();
+        let endpoints: Vec<Arc<Endpoint>> = config.upstream_urls
+            .iter()
+            .map(|upstream| {
+                if let Some(index) = previous
+                    .iter()
+                    .position(|endpoint| endpoint.url == upstream.url() && endpoint.weight == upstream.weight())
+                {
+                    return previous.remove(index);
+                }
+                let endpoint = Endpoint::new(upstream.url(), upstream.weight());
+                match &config.circuit_breaker {
+                    Some(circuit) => Arc::new(endpoint.with_circuit_breaker(circuit)),
+                    None => Arc::new(endpoint),
+                }
+            })
+            .collect();
+        let load_balancer = balancer::build(config.load_balancing, &endpoints);
+        let len = endpoints.len();
+        let mut service = Self::with_balancer(
+            config.prefix.clone(),
+            endpoints,
+            load_balancer,
+            config.timeout_seconds,
+            config.max_retries.unwrap_or(len),
+        );
+        service.matcher = Arc::new(RouteMatcher::from_config(&config.matcher));
+        service.route_key = config.route_key();
+        service.default_route = config.default_route;
+        // Validated as method tokens when the configuration was loaded
+        service.allowed_methods = config.allowed_methods.as_ref().map(|methods| {
+            methods.iter().filter_map(|method| Method::from_bytes(method.as_bytes()).ok()).collect()
+        });
+        service.timeouts = Timeouts::from_config(config.timeout_seconds, &config.timeouts.or(default_timeouts));
+        service.max_replay_body_bytes = config.max_replay_body_bytes;
+        service.retry_policy = RetryPolicy::new(&config.retry);
+        service.retry_budget = config.retry_budget
+            .as_ref()
+            .map(|budget| Arc::new(RetryBudget::new(&config.prefix, budget)));
+        service.hedge = config.hedge.as_ref().map(|hedge| Arc::new(HedgePolicy::new(hedge)));
+        service.streaming = config.streaming.clone();
+        service.upgrade = config.upgrade
+            .as_ref()
+            .map(|upgrade| Arc::new(UpgradePolicy::new(&config.prefix, upgrade)));
+        service.rewrite = config.rewrite.as_ref().map(|rewrite| Arc::new(Rewrite::from_config(rewrite)));
+        service.forward_upstream_errors = config.forward_upstream_errors;
+        service.hash_key = config.hash_key.clone();
+        service.sticky_session = config.sticky_session
+            .as_ref()
+            .map(|sticky| StickySession::from_config(sticky, &config.prefix));
+        service.outlier_detector = config.outlier_detection
+            .as_ref()
+            .map(|outlier| Arc::new(OutlierDetector::new(&config.prefix, &service.endpoints, outlier)));
+        service.circuit_breaker = config.circuit_breaker
+            .as_ref()
+            .map(|circuit| Arc::new(CircuitBreaker::new(&config.prefix, circuit)));
+        service
+    }
+
+    /// Round-robin service over equally weighted upstream URLs.
+    pub fn with_config(prefix: impl Into<String>, upstream_urls: Vec<String>, timeout_seconds: u64, max_retries: usize) -> Self {
+        let endpoints = upstream_urls
+            .into_iter()
+            .map(|url| Arc::new(Endpoint::new(url, 1)))
+            .collect();
+        Self::with_balancer(prefix, endpoints, Arc::new(RoundRobin::new()), timeout_seconds, max_retries)
+    }
+
+    pub fn with_balancer(
+        prefix: impl Into<String>,
+        endpoints: Vec<Arc<Endpoint>>,
+        load_balancer: Arc<dyn LoadBalancer>,
+        timeout_seconds: u64,
+        max_retries: usize,
+    ) -> Self {
+        let len = endpoints.len();
+        let prefix = prefix.into();
+        Self {
+            route_key: prefix.clone(),
+            prefix,
+            matcher: Arc::new(RouteMatcher::default()),
+            default_route: false,
+            split: None,
+            allowed_methods: None,
+            endpoints,
+            load_balancer,
+            hash_key: None,
+            sticky_session: None,
+            health_checker: None,
+            outlier_detector: None,
+            circuit_breaker: None,
+            timeouts: Timeouts::new(Duration::from_secs(timeout_seconds)),
+            max_retries: max_retries.min(len), // Don't retry more than available servers
+            max_replay_body_bytes: DEFAULT_MAX_REPLAY_BODY_BYTES,
+            retry_policy: RetryPolicy::default(),
+            retry_budget: None,
+            hedge: None,
+            streaming: StreamingConfig::default(),
+            upgrade: None,
+            rewrite: None,
+            forward_upstream_errors: true,
+        }
+    }
+
+    /// Picks the endpoint for the next attempt, skipping the ones already tried.
+    pub fn pick(&self, tried: &[usize]) -> Option<usize> {
+        self.pick_for(&PickContext { tried, ..Default::default() })
+    }
+
+    /// Feeds the outcome of an attempt to outlier detection.
+    pub fn record_outcome(&self, index: usize, success: bool) {
+        if let Some(detector) = &self.outlier_detector {
+            detector.record(index, success);
+        }
+    }
+
+    pub fn pick_for(&self, ctx: &PickContext<'_>) -> Option<usize> {
+        if let Some(index) = self.load_balancer.pick(&self.endpoints, ctx) {
+            return Some(index);
+        }
+
+        // Every untried endpoint is marked down, try them anyway rather than failing outright
+        if !ctx.include_unavailable && ctx.tried.len() < self.endpoints.len() {
+            tracing::warn!("no available upstream for {}, routing to unavailable upstreams", self.prefix);
+            return self.load_balancer.pick(&self.endpoints, &PickContext { include_unavailable: true, ..*ctx });
+        }
+        None
+    }
+}
+
+/// Builds the routing table and starts health checks, must run inside the tokio runtime.
+pub fn build_upstream_services(config: &GatewayConfig) -> Vec<UpstreamService> {
+    config.services
+        .iter()
+        .map(|service_config| build_route(service_config, &config.timeouts, None))
+        .collect()
+}
+
+/// Builds the routing table for a reloaded configuration. Unchanged services
+/// are kept as they are, changed ones keep the state of their unchanged
+/// upstreams, and the health checks of the services they replace are stopped.
+///
+/// `routes` has to be the table built from `previous`.
+pub fn reload_upstream_services(config: &GatewayConfig, previous: &GatewayConfig, routes: &RouteTable) -> Vec<UpstreamService> {
+    let services: Vec<UpstreamService> = config.services
+        .iter()
+        .map(|service_config| {
+            let key = service_config.route_key();
+            let old_config = previous.services.iter().find(|old_config| old_config.route_key() == key);
+            let old = old_config.zip(routes.service(&key));
+            match old {
+                Some((old_config, old_service)) if old_config == service_config && previous.timeouts == config.timeouts => {
+                    old_service.clone()
+                }
+                old => build_route(service_config, &config.timeouts, old),
+            }
+        })
+        .collect();
+
+    // Requests still in flight can keep replaced services alive for a while,
+    // their probes would race the new ones over the same endpoints
+    let running: Vec<&Arc<HealthChecker>> = services.iter().flat_map(health_checkers).collect();
+    for checker in routes.services().iter().flat_map(health_checkers) {
+        if !running.iter().any(|running| Arc::ptr_eq(running, checker)) {
+            checker.stop();
+        }
+    }
+    services
+}
+
+/// Builds a service and the clusters of its split, taking over what is
+/// unchanged from `previous`.
+fn build_route(
+    config: &ServiceConfig,
+    default_timeouts: &TimeoutConfig,
+    previous: Option<(&ServiceConfig, &UpstreamService)>,
+) -> UpstreamService {
+    let mut service = build_service(config, default_timeouts, previous);
+    if let Some(split) = &config.split {
+        let clusters = split.clusters
+            .iter()
+            .map(|cluster| {
+                let cluster_config = cluster_service_config(config, cluster);
+                // The cluster of the same name in the previous split
+                let previous = previous.and_then(|(old_config, old_service)| {
+                    let old_cluster = old_config.split.as_ref()?.clusters.iter().find(|old| old.name == cluster.name)?;
+                    let old_split = old_service.split.as_ref()?;
+                    let old_service = &old_split.clusters().iter().find(|old| old.name == cluster.name)?.service;
+                    Some((cluster_service_config(old_config, old_cluster), old_service))
+                });
+                let previous = previous.as_ref().map(|(old_config, old_service)| (old_config, *old_service));
+                let mut cluster_service = build_service(&cluster_config, default_timeouts, previous);
+                cluster_service.sticky_session = service.sticky_session.clone();
+                cluster_service
+            })
+            .collect();
+        service.split = Some(Arc::new(TrafficSplit::new(&config.prefix, split, clusters)));
+    }
+    service
+}
+
+/// Each cluster gets the settings of the service, named after both in logs and metrics.
+fn cluster_service_config(config: &ServiceConfig, cluster: &ClusterConfig) -> ServiceConfig {
+    ServiceConfig {
+        prefix: format!("{} ({})", config.prefix, cluster.name),
+        upstream_urls: cluster.upstream_urls.clone(),
+        split: None,
+        ..config.clone()
+    }
+}
+
+fn build_service(
+    config: &ServiceConfig,
+    default_timeouts: &TimeoutConfig,
+    previous: Option<(&ServiceConfig, &UpstreamService)>,
+) -> UpstreamService {
+    let mut service = match previous {
+        // Endpoints own their circuit breakers, so they can only be kept while those stay the same
+        Some((old_config, old_service)) if old_config.circuit_breaker == config.circuit_breaker => {
+            UpstreamService::from_config_reusing(config, default_timeouts, &old_service.endpoints)
+        }
+        _ => UpstreamService::from_config(config, default_timeouts),
+    };
+    if let Some((old_config, old_service)) = previous {
+        if old_config.circuit_breaker == config.circuit_breaker {
+            service.circuit_breaker = old_service.circuit_breaker.clone();
+        }
+        if old_config.retry_budget == config.retry_budget {
+            service.retry_budget = old_service.retry_budget.clone();
+        }
+        let same_endpoints = service.endpoints.len() == old_service.endpoints.len()
+            && service.endpoints.iter().zip(&old_service.endpoints).all(|(new, old)| Arc::ptr_eq(new, old));
+        if old_config.outlier_detection == config.outlier_detection && same_endpoints {
+            service.outlier_detector = old_service.outlier_detector.clone();
+        }
+    }
+    match &config.health_check {
+        Some(health_check) => {
+            service.health_checker = Some(Arc::new(HealthChecker::spawn(&service.prefix, &service.endpoints, health_check)));
+        }
+        // Nothing would clear a mark kept from the previous health checks
+        None => {
+            for endpoint in &service.endpoints {
+                endpoint.set_healthy(true);
+            }
+        }
+    }
+    service
+}
+
+/// The health checkers of a service and the clusters of its split.
+fn health_checkers(service: &UpstreamService) -> Vec<&Arc<HealthChecker>> {
+    let clusters = service.split.iter().flat_map(|split| split.clusters()).map(|cluster| &cluster.service);
+    std::iter::once(service)
+        .chain(clusters)
+        .filter_map(|service| service.health_checker.as_ref())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use leyline_config::ConfigFormat;
+
+    fn config(services: &str) -> GatewayConfig {
+        let toml = format!("[[listeners]]\naddress = \"127.0.0.1:3000\"\n{}", services);
+        GatewayConfig::from_str_with_format(&toml, ConfigFormat::Toml).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_reload_keeps_unchanged_state() {
+        let previous = config(r#"
+            [[services]]
+            prefix = "/py"
+            upstream_urls = ["http://127.0.0.1:8081", "http://127.0.0.1:8082"]
+            [services.health_check]
+            interval_seconds = 3600
+
+            [[services]]
+            prefix = "/go"
+            upstream_urls = ["http://127.0.0.1:8083"]
+            [services.health_check]
+            interval_seconds = 3600
+        "#);
+        let routes = RouteTable::new(build_upstream_services(&previous));
+        let old = routes.services();
+        old[0].endpoints[1].set_healthy(false);
+
+        let config = config(r#"
+            [[services]]
+            prefix = "/py"
+            upstream_urls = ["http://127.0.0.1:8082", "http://127.0.0.1:8084"]
+            [services.health_check]
+            interval_seconds = 3600
+
+            [[services]]
+            prefix = "/go"
+            upstream_urls = ["http://127.0.0.1:8083"]
+            [services.health_check]
+            interval_seconds = 3600
+        "#);
+        let services = reload_upstream_services(&config, &previous, &routes);
+
+        // The changed service keeps the upstream it still has, unhealthy as it was
+        assert!(Arc::ptr_eq(&services[0].endpoints[0], &old[0].endpoints[1]));
+        assert!(!services[0].endpoints[0].is_healthy());
+        assert_eq!(services[0].endpoints[1].url, "http://127.0.0.1:8084");
+        assert!(!Arc::ptr_eq(services[0].health_checker.as_ref().unwrap(), old[0].health_checker.as_ref().unwrap()));
+        let old_checker = old[0].health_checker.as_ref().unwrap();
+        for _ in 0..100 {
+            if old_checker.is_stopped() {
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+        assert!(old_checker.is_stopped());
+
+        // The unchanged one is kept as it is, probes included
+        assert!(!old[1].health_checker.as_ref().unwrap().is_stopped());
+        assert!(Arc::ptr_eq(services[1].health_checker.as_ref().unwrap(), old[1].health_checker.as_ref().unwrap()));
+        assert!(Arc::ptr_eq(&services[1].endpoints[0], &old[1].endpoints[0]));
+    }
+
+    #[test]
+    fn test_reload_after_reorder() {
+        let a = "[[services]]\nprefix = \"/a\"\nupstream_urls = [\"http://a:1\"]\n";
+        let b = "[[services]]\nprefix = \"/b\"\nupstream_urls = [\"http://b:1\"]\n";
+        let previous = config(&format!("{}{}", a, b));
+        let routes = RouteTable::new(build_upstream_services(&previous));
+
+        let reordered = config(&format!("{}{}", b, a));
+        let services = reload_upstream_services(&reordered, &previous, &routes);
+        assert!(Arc::ptr_eq(&services[0].endpoints[0], &routes.services()[1].endpoints[0]));
+        assert!(Arc::ptr_eq(&services[1].endpoints[0], &routes.services()[0].endpoints[0]));
+        let routes = RouteTable::new(services);
+
+        let changed = config(&format!("{}timeout_seconds = 5\n{}", b, a));
+        let services = reload_upstream_services(&changed, &reordered, &routes);
+        assert_eq!(services[0].prefix, "/b");
+        assert_eq!(services[0].endpoints[0].url, "http://b:1");
+        assert_eq!(services[0].timeouts.total, Duration::from_secs(5));
+        assert_eq!(services[1].endpoints[0].url, "http://a:1");
+    }
+}
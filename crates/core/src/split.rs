@@ -0,0 +1,215 @@
+use crate::balancer::hash::{cookie_value, request_hash};
+use crate::metrics::{Counter, Registry};
+use crate::service::UpstreamService;
+use axum::http::{HeaderMap, Uri};
+use leyline_config::{HashKey, SplitConfig};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+// Users are placed on a scale this fine, so raising a cluster's weight only
+// moves the users at the edge of its share over to it
+const BUCKETS: u64 = 10_000;
+
+/// A route's traffic divided across clusters of upstreams by weight. Weights
+/// can be changed while running, until a reload changes the route.
+#[derive(Debug)]
+pub struct TrafficSplit {
+    clusters: Vec<Cluster>,
+    user_key: Option<HashKey>,
+    override_header: Option<String>,
+    override_cookie: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct Cluster {
+    pub name: String,
+    weight: AtomicU32,
+    pub service: UpstreamService,
+    requests: Counter,
+}
+
+impl TrafficSplit {
+    /// Builds the split from a validated configuration, with `services` built
+    /// for its clusters in the same order.
+    pub fn new(prefix: &str, config: &SplitConfig, services: Vec<UpstreamService>) -> Self {
+        let registry = Registry::global();
+        let clusters = config.clusters
+            .iter()
+            .zip(services)
+            .map(|(cluster, service)| Cluster {
+                name: cluster.name.clone(),
+                weight: AtomicU32::new(cluster.weight),
+                service,
+                requests: registry.counter(
+                    "leyline_split_requests_total",
+                    "Requests sent to each cluster of a traffic split.",
+                    &[("service", prefix), ("cluster", &cluster.name)],
+                ),
+            })
+            .collect();
+        Self {
+            clusters,
+            user_key: config.user_key.clone(),
+            override_header: config.override_header.clone(),
+            override_cookie: config.override_cookie.clone(),
+        }
+    }
+
+    pub fn clusters(&self) -> &[Cluster] {
+        &self.clusters
+    }
+
+    /// Sets the weights of the named clusters, leaving the others as they are.
+    /// Nothing changes if a name is unknown or every weight would be 0.
+    pub fn set_weights(&self, weights: &[(String, u32)]) -> Result<(), String> {
+        self.check_weights(weights)?;
+        for (name, weight) in weights {
+            if let Some(cluster) = self.cluster(name) {
+                cluster.weight.store(*weight, Ordering::Relaxed);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `set_weights` would accept `weights`.
+    pub fn check_weights(&self, weights: &[(String, u32)]) -> Result<(), String> {
+        if let Some((name, _)) = weights.iter().find(|(name, _)| self.cluster(name).is_none()) {
+            return Err(format!("unknown cluster '{}'", name));
+        }
+        let total: u64 = self.clusters
+            .iter()
+            .map(|cluster| {
+                let weight = weights.iter().find(|(name, _)| *name == cluster.name).map_or(cluster.weight(), |(_, weight)| *weight);
+                u64::from(weight)
+            })
+            .sum();
+        if total == 0 {
+            return Err("at least one cluster needs a weight greater than 0".to_string());
+        }
+        Ok(())
+    }
+
+    /// The cluster for a request: the one its override header or cookie names,
+    /// otherwise one picked by weight, by the user key when the request has one.
+    /// An override that names no cluster is ignored.
+    pub fn choose(&self, headers: &HeaderMap, uri: &Uri, client_ip: Option<IpAddr>) -> &Cluster {
+        let forced = self.override_header
+            .as_ref()
+            .and_then(|name| headers.get(name.as_str())?.to_str().ok())
+            .and_then(|name| self.cluster(name.trim()))
+            .or_else(|| self.cluster(cookie_value(headers, self.override_cookie.as_ref()?)?.trim()));
+        let cluster = forced.unwrap_or_else(|| {
+            let hash = self.user_key
+                .as_ref()
+                .and_then(|key| request_hash(key, headers, uri, client_ip))
+                .unwrap_or_else(rand::random);
+            self.pick(hash % BUCKETS)
+        });
+        cluster.requests.inc();
+        cluster
+    }
+
+    /// The cluster whose share of the buckets contains `bucket`.
+    fn pick(&self, bucket: u64) -> &Cluster {
+        let weights: Vec<u64> = self.clusters.iter().map(|cluster| u64::from(cluster.weight())).collect();
+        let total: u64 = weights.iter().sum();
+        let mut upper = 0;
+        for (cluster, weight) in self.clusters.iter().zip(weights) {
+            upper += weight;
+            if bucket * total < upper * BUCKETS {
+                return cluster;
+            }
+        }
+        // Only reachable while weights are being changed
+        &self.clusters[0]
+    }
+
+    fn cluster(&self, name: &str) -> Option<&Cluster> {
+        self.clusters.iter().find(|cluster| cluster.name == name)
+    }
+}
+
+impl Cluster {
+    pub fn weight(&self) -> u32 {
+        self.weight.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::balancer::hash::hash_bytes;
+    use leyline_config::ClusterConfig;
+
+    fn split(weights: &[(&str, u32)]) -> TrafficSplit {
+        let config = SplitConfig {
+            clusters: weights
+                .iter()
+                .map(|(name, weight)| ClusterConfig { name: name.to_string(), weight: *weight, upstream_urls: Vec::new() })
+                .collect(),
+            user_key: Some(HashKey::Header("x-user".to_string())),
+            override_header: Some("x-cluster".to_string()),
+            override_cookie: Some("cluster".to_string()),
+        };
+        let services = weights
+            .iter()
+            .map(|(name, _)| UpstreamService::with_config(*name, vec![format!("http://{}", name)], 1, 1))
+            .collect();
+        TrafficSplit::new("/test", &config, services)
+    }
+
+    fn choose(split: &TrafficSplit, headers: &[(&'static str, &str)]) -> String {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.insert(*name, value.parse().unwrap());
+        }
+        split.choose(&map, &Uri::from_static("/"), None).name.clone()
+    }
+
+    #[test]
+    fn test_weights_divide_buckets() {
+        let split = split(&[("stable", 95), ("canary", 5)]);
+        let canary = (0..BUCKETS).filter(|&bucket| split.pick(bucket).name == "canary").count();
+        assert_eq!(canary, 500);
+
+        // Raising the canary's weight keeps its users and adds the ones next to them
+        let before: Vec<u64> = (0..BUCKETS).filter(|&bucket| split.pick(bucket).name == "canary").collect();
+        split.set_weights(&[("canary".to_string(), 10)]).unwrap();
+        assert!(before.iter().all(|&bucket| split.pick(bucket).name == "canary"));
+        let canary = (0..BUCKETS).filter(|&bucket| split.pick(bucket).name == "canary").count();
+        assert_eq!(canary, 952);
+    }
+
+    #[test]
+    fn test_users_stay_on_one_cluster() {
+        let split = split(&[("stable", 1), ("canary", 1)]);
+        let users: Vec<String> = (0..20).map(|user| format!("user-{}", user)).collect();
+        for user in &users {
+            let expected = split.pick(hash_bytes(user.as_bytes()) % BUCKETS).name.clone();
+            for _ in 0..5 {
+                assert_eq!(choose(&split, &[("x-user", user)]), expected);
+            }
+        }
+        let canary = users.iter().filter(|user| choose(&split, &[("x-user", user)]) == "canary").count();
+        assert!(canary > 0 && canary < users.len());
+    }
+
+    #[test]
+    fn test_overrides_and_weight_changes() {
+        let split = split(&[("stable", 1), ("canary", 0)]);
+        assert_eq!(choose(&split, &[("x-user", "anyone")]), "stable");
+        assert_eq!(choose(&split, &[("x-cluster", "canary")]), "canary");
+        assert_eq!(choose(&split, &[("cookie", "a=1; cluster=canary")]), "canary");
+        // Unknown names fall back to the weights
+        assert_eq!(choose(&split, &[("x-cluster", "blue")]), "stable");
+        assert_eq!(choose(&split, &[("x-cluster", "blue"), ("cookie", "cluster=canary")]), "canary");
+        assert_eq!(choose(&split, &[("x-cluster", "canary"), ("cookie", "cluster=stable")]), "canary");
+
+        assert_eq!(split.set_weights(&[("blue".to_string(), 1)]), Err("unknown cluster 'blue'".to_string()));
+        assert!(split.set_weights(&[("stable".to_string(), 0)]).is_err());
+        split.set_weights(&[("stable".to_string(), 0), ("canary".to_string(), 3)]).unwrap();
+        assert_eq!(choose(&split, &[]), "canary");
+        let weights: Vec<u32> = split.clusters().iter().map(Cluster::weight).collect();
+        assert_eq!(weights, [0, 3]);
+    }
+}
@@ -0,0 +1,180 @@
+use crate::balancer::hash::{cookie_value, hash_bytes};
+use crate::endpoint::Endpoint;
+use axum::http::{HeaderMap, HeaderValue};
+use hmac::{Hmac, Mac};
+use leyline_config::StickySessionConfig;
+use sha2::Sha256;
+use std::sync::Arc;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Issues and verifies the affinity cookie for one upstream service.
+///
+/// The cookie value is `<upstream id>.<signature>`, where the id is a hash of the
+/// upstream URL, so internal addresses are not exposed and ids survive reloads.
+#[derive(Debug, Clone)]
+pub struct StickySession {
+    cookie_name: String,
+    secret: Vec<u8>,
+    max_age_seconds: Option<u64>,
+    path: String,
+}
+
+impl StickySession {
+    /// Scopes the cookie to the routes under `prefix`, which may be a path template.
+    pub fn from_config(config: &StickySessionConfig, prefix: &str) -> Self {
+        // Browsers only match cookie paths literally, so stop at the segment
+        // holding the first path parameter
+        let path = match prefix.find('{') {
+            Some(start) => &prefix[..prefix[..start].rfind('/').unwrap_or(0)],
+            None => prefix,
+        };
+        Self {
+            cookie_name: config.cookie_name.clone(),
+            secret: config.secret.as_bytes().to_vec(),
+            max_age_seconds: config.max_age_seconds,
+            path: if path.is_empty() { "/".to_string() } else { path.to_string() },
+        }
+    }
+
+    fn endpoint_id(endpoint: &Endpoint) -> String {
+        format!("{:016x}", hash_bytes(endpoint.url.as_bytes()))
+    }
+
+    fn mac(&self, id: &str) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.secret).expect("HMAC accepts any key length");
+        mac.update(id.as_bytes());
+        mac
+    }
+
+    pub fn cookie_value(&self, endpoint: &Endpoint) -> String {
+        let id = Self::endpoint_id(endpoint);
+        let signature: String = self.mac(&id)
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        format!("{}.{}", id, signature)
+    }
+
+    /// Returns the endpoint named by a validly signed cookie, if it is still configured.
+    pub fn lookup(&self, headers: &HeaderMap, endpoints: &[Arc<Endpoint>]) -> Option<usize> {
+        let value = cookie_value(headers, &self.cookie_name)?;
+        let (id, signature) = value.split_once('.')?;
+
+        let signature = decode_hex(signature)?;
+        if self.mac(id).verify_slice(&signature).is_err() {
+            tracing::debug!("ignoring affinity cookie with invalid signature");
+            return None;
+        }
+
+        endpoints.iter().position(|endpoint| Self::endpoint_id(endpoint) == id)
+    }
+
+    pub fn set_cookie(&self, endpoint: &Endpoint) -> HeaderValue {
+        let mut cookie = format!(
+            "{}={}; Path={}; HttpOnly; SameSite=Lax",
+            self.cookie_name,
+            self.cookie_value(endpoint),
+            self.path
+        );
+        if let Some(max_age) = self.max_age_seconds {
+            cookie.push_str(&format!("; Max-Age={}", max_age));
+        }
+        HeaderValue::from_str(&cookie).expect("cookie is built from validated ASCII")
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sticky_at(prefix: &str) -> StickySession {
+        let config = StickySessionConfig {
+            cookie_name: "leyline_affinity".to_string(),
+            secret: "0123456789abcdef".to_string(),
+            max_age_seconds: Some(3600),
+        };
+        StickySession::from_config(&config, prefix)
+    }
+
+    fn sticky() -> StickySession {
+        sticky_at("/py")
+    }
+
+    fn endpoints() -> Vec<Arc<Endpoint>> {
+        vec![
+            Arc::new(Endpoint::new("http://127.0.0.1:8081", 1)),
+            Arc::new(Endpoint::new("http://127.0.0.1:8082", 1)),
+        ]
+    }
+
+    fn cookie_headers(cookie: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("cookie", HeaderValue::from_str(cookie).unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_cookie_round_trip() {
+        let sticky = sticky();
+        let endpoints = endpoints();
+
+        let set_cookie = sticky.set_cookie(&endpoints[1]);
+        let set_cookie = set_cookie.to_str().unwrap();
+        assert!(set_cookie.ends_with("; Path=/py; HttpOnly; SameSite=Lax; Max-Age=3600"));
+
+        let pair = set_cookie.split(';').next().unwrap();
+        let headers = cookie_headers(&format!("theme=dark; {}", pair));
+        assert_eq!(sticky.lookup(&headers, &endpoints), Some(1));
+    }
+
+    #[test]
+    fn test_cookie_path_of_templated_prefix() {
+        let endpoint = Endpoint::new("http://127.0.0.1:8081", 1);
+        let path = |prefix| {
+            let set_cookie = sticky_at(prefix).set_cookie(&endpoint);
+            set_cookie.to_str().unwrap().split("; ").nth(1).unwrap().to_string()
+        };
+        assert_eq!(path("/accounts/{id}/orders"), "Path=/accounts");
+        assert_eq!(path("/{tenant}/api"), "Path=/");
+        assert_eq!(path("/u-{id}"), "Path=/");
+        assert_eq!(path("/"), "Path=/");
+    }
+
+    #[test]
+    fn test_tampered_cookie_is_ignored() {
+        let sticky = sticky();
+        let endpoints = endpoints();
+
+        // Reuse the signature of upstream 0 with the id of upstream 1
+        let value = sticky.cookie_value(&endpoints[0]);
+        let (_, signature) = value.split_once('.').unwrap();
+        let id = sticky.cookie_value(&endpoints[1]).split_once('.').unwrap().0.to_string();
+        let headers = cookie_headers(&format!("leyline_affinity={}.{}", id, signature));
+        assert_eq!(sticky.lookup(&headers, &endpoints), None);
+
+        assert_eq!(sticky.lookup(&cookie_headers("leyline_affinity=garbage"), &endpoints), None);
+    }
+
+    #[test]
+    fn test_removed_upstream_is_not_found() {
+        let sticky = sticky();
+        let endpoints = endpoints();
+        let value = sticky.cookie_value(&endpoints[1]);
+
+        let headers = cookie_headers(&format!("leyline_affinity={}", value));
+        assert_eq!(sticky.lookup(&headers, &endpoints[..1]), None);
+    }
+}
@@ -0,0 +1,34 @@
+use leyline_config::LoggingConfig;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+
+/// Initializes tracing with console and file output.
+///
+/// The returned guard flushes the file writer and must be kept alive.
+pub fn init(logging: &LoggingConfig) -> WorkerGuard {
+    let file_appender = tracing_appender::rolling::daily(&logging.directory, &logging.file_name);
+    let (non_blocking, guard) = tracing_appender::non_blocking(file_appender);
+
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| logging.filter.as_str().into()),
+        )
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_target(false)
+                .with_thread_ids(false)
+                .with_thread_names(false)
+        )
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_writer(non_blocking)
+                .with_target(false)
+                .with_thread_ids(false)
+                .with_thread_names(false)
+                .json()
+        )
+        .init();
+
+    guard
+}
@@ -0,0 +1,222 @@
+use crate::metrics::{Counter, Gauge, Registry};
+use axum::http::{header, HeaderMap};
+use leyline_config::UpgradeConfig;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Instant;
+
+/// Which `Upgrade` handshakes a service proxies, and how long the upgraded
+/// connections may sit idle.
+#[derive(Debug)]
+pub struct UpgradePolicy {
+    protocols: Vec<String>,
+    pub idle_timeout: Duration,
+    active: Gauge,
+    opened: Counter,
+}
+
+impl UpgradePolicy {
+    pub fn new(prefix: &str, config: &UpgradeConfig) -> Self {
+        let registry = Registry::global();
+        Self {
+            protocols: config.protocols.clone(),
+            idle_timeout: Duration::from_secs(config.idle_timeout_seconds),
+            active: registry.gauge(
+                "leyline_upgraded_connections",
+                "Upgraded connections, such as WebSockets, currently open.",
+                &[("service", prefix)],
+            ),
+            opened: registry.counter(
+                "leyline_upgraded_connections_total",
+                "Upgraded connections, such as WebSockets, opened since startup.",
+                &[("service", prefix)],
+            ),
+        }
+    }
+
+    pub fn allows(&self, protocol: &str) -> bool {
+        self.protocols.iter().any(|allowed| allowed.eq_ignore_ascii_case(protocol))
+    }
+
+    /// Counts an upgraded connection as open until the guard is dropped.
+    pub fn open_connection(&self) -> ConnectionGuard {
+        self.opened.inc();
+        self.active.inc();
+        ConnectionGuard(self.active.clone())
+    }
+}
+
+#[derive(Debug)]
+pub struct ConnectionGuard(Gauge);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+/// The protocol a request asks to switch to, `None` unless it is an upgrade handshake.
+pub fn requested_protocol(headers: &HeaderMap) -> Option<&str> {
+    let upgrade = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case("upgrade"));
+    if !upgrade {
+        return None;
+    }
+    // Clients may offer several protocols, the first one is the preferred
+    let protocol = headers.get(header::UPGRADE)?.to_str().ok()?.split(',').next()?.trim();
+    (!protocol.is_empty()).then_some(protocol)
+}
+
+/// Copies data both ways between the client and upstream connections until
+/// both sides have closed, or nothing was sent either way for `idle_timeout`.
+/// Returns the bytes sent from the client to the upstream and back.
+pub async fn splice<C, U>(client: C, upstream: U, idle_timeout: Duration) -> io::Result<(u64, u64)>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    U: AsyncRead + AsyncWrite + Unpin,
+{
+    let activity = Activity::new();
+    let mut client = Tracked { inner: client, activity: &activity };
+    let mut upstream = Tracked { inner: upstream, activity: &activity };
+    let copy = tokio::io::copy_bidirectional(&mut client, &mut upstream);
+    tokio::pin!(copy);
+
+    loop {
+        let idle_until = activity.last() + idle_timeout;
+        tokio::select! {
+            result = &mut copy => return result,
+            _ = tokio::time::sleep_until(idle_until) => {
+                if activity.last() + idle_timeout <= Instant::now() {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "no traffic within the idle timeout"));
+                }
+            }
+        }
+    }
+}
+
+/// When data last moved over a spliced connection.
+struct Activity {
+    started: Instant,
+    last_millis: AtomicU64,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            last_millis: AtomicU64::new(0),
+        }
+    }
+
+    fn touch(&self) {
+        let millis = self.started.elapsed().as_millis() as u64;
+        self.last_millis.store(millis, Ordering::Relaxed);
+    }
+
+    fn last(&self) -> Instant {
+        self.started + Duration::from_millis(self.last_millis.load(Ordering::Relaxed))
+    }
+}
+
+/// A connection that records its reads and writes as activity.
+struct Tracked<'a, S> {
+    inner: S,
+    activity: &'a Activity,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Tracked<'_, S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if buf.filled().len() > filled {
+            self.activity.touch();
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Tracked<'_, S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = result
+            && written > 0
+        {
+            self.activity.touch();
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_requested_protocol() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::UPGRADE, "websocket".parse().unwrap());
+        assert_eq!(requested_protocol(&headers), None);
+
+        headers.insert(header::CONNECTION, "keep-alive, Upgrade".parse().unwrap());
+        assert_eq!(requested_protocol(&headers), Some("websocket"));
+
+        headers.insert(header::UPGRADE, "h2c, websocket".parse().unwrap());
+        assert_eq!(requested_protocol(&headers), Some("h2c"));
+    }
+
+    #[tokio::test]
+    async fn test_splice_copies_both_ways() {
+        let (mut client, client_side) = tokio::io::duplex(64);
+        let (upstream_side, mut upstream) = tokio::io::duplex(64);
+        let splice = tokio::spawn(splice(client_side, upstream_side, Duration::from_secs(5)));
+
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        upstream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        upstream.write_all(b"pong!").await.unwrap();
+        let mut buf = [0; 5];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"pong!");
+
+        drop(client);
+        drop(upstream);
+        assert_eq!(splice.await.unwrap().unwrap(), (4, 5));
+    }
+
+    #[tokio::test]
+    async fn test_splice_closes_idle_connections() {
+        let (mut client, client_side) = tokio::io::duplex(64);
+        let (upstream_side, _upstream) = tokio::io::duplex(64);
+        let splice = tokio::spawn(splice(client_side, upstream_side, Duration::from_millis(50)));
+
+        // Traffic keeps the connection open past the idle timeout
+        for _ in 0..4 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            client.write_all(b"x").await.unwrap();
+        }
+        assert!(!splice.is_finished());
+
+        let error = splice.await.unwrap().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        let mut buf = Vec::new();
+        assert_eq!(client.read_to_end(&mut buf).await.unwrap(), 0);
+    }
+}
@@ -1,15 +1,22 @@
 use axum::{
-    http::StatusCode,
+    http::{header, HeaderValue, StatusCode},
     response::{IntoResponse, Response},
     Json,
 };
 use serde_json::json;
+use std::time::Duration;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum GatewayError {
     #[error("HTTP request failed: {0}")]
-    HttpRequest(#[from] reqwest::Error),
+    HttpRequest(#[from] hyper_util::client::legacy::Error),
+
+    #[error("Reading the upstream response failed: {0}")]
+    UpstreamBody(#[from] hyper::Error),
+
+    #[error("TLS setup failed: {0}")]
+    Tls(#[from] hyper_tls::native_tls::Error),
 
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
@@ -17,8 +24,29 @@ pub enum GatewayError {
     #[error("Invalid URI: {0}")]
     InvalidUri(#[from] axum::http::uri::InvalidUri),
 
-    #[error("Upstream timeout")]
-    Timeout,
+    #[error("Upstream server returned error status {0}")]
+    UpstreamStatus(u16),
+
+    #[error("Timed out connecting to upstream {0}")]
+    ConnectTimeout(String),
+
+    #[error("Upstream {0} did not send response headers in time")]
+    FirstByteTimeout(String),
+
+    #[error("Upstream {0} stalled while sending the response body")]
+    BodyIdleTimeout(String),
+
+    #[error("Request deadline exceeded")]
+    DeadlineExceeded,
+
+    #[error("No route for {method} {path}")]
+    NoRoute { method: String, path: String },
+
+    #[error("Method {method} is not allowed")]
+    MethodNotAllowed { method: String, allowed: Vec<String> },
+
+    #[error("Circuit breaker open for {target}")]
+    CircuitOpen { target: String, retry_after: Duration },
 
     #[error("Configuration error: {0}")]
     Config(String),
@@ -30,10 +58,16 @@ pub enum GatewayError {
 impl IntoResponse for GatewayError {
     fn into_response(self) -> Response {
         let (status, error_message) = match self {
-            GatewayError::HttpRequest(_) => (StatusCode::BAD_GATEWAY, "Bad Gateway"),
-            GatewayError::Io(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error"),
+            GatewayError::HttpRequest(_) | GatewayError::UpstreamBody(_) | GatewayError::UpstreamStatus(_) => (StatusCode::BAD_GATEWAY, "Bad Gateway"),
+            GatewayError::Io(_) | GatewayError::Tls(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error"),
             GatewayError::InvalidUri(_) => (StatusCode::BAD_REQUEST, "Bad Request"),
-            GatewayError::Timeout => (StatusCode::GATEWAY_TIMEOUT, "Gateway Timeout"),
+            GatewayError::ConnectTimeout(_)
+            | GatewayError::FirstByteTimeout(_)
+            | GatewayError::BodyIdleTimeout(_)
+            | GatewayError::DeadlineExceeded => (StatusCode::GATEWAY_TIMEOUT, "Gateway Timeout"),
+            GatewayError::NoRoute { .. } => (StatusCode::NOT_FOUND, "Not Found"),
+            GatewayError::MethodNotAllowed { .. } => (StatusCode::METHOD_NOT_ALLOWED, "Method Not Allowed"),
+            GatewayError::CircuitOpen { .. } => (StatusCode::SERVICE_UNAVAILABLE, "Service Unavailable"),
             GatewayError::Config(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Configuration Error"),
             GatewayError::Internal => (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error"),
         };
@@ -43,6 +77,20 @@ impl IntoResponse for GatewayError {
             "message": self.to_string(),
         }));
 
-        (status, body).into_response()
+        let mut response = (status, body).into_response();
+        match self {
+            GatewayError::CircuitOpen { retry_after, .. } => {
+                // Whole seconds, rounded up so clients don't come back while still open
+                let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+                response.headers_mut().insert(header::RETRY_AFTER, seconds.max(1).into());
+            }
+            GatewayError::MethodNotAllowed { allowed, .. } => {
+                if let Ok(allow) = HeaderValue::from_str(&allowed.join(", ")) {
+                    response.headers_mut().insert(header::ALLOW, allow);
+                }
+            }
+            _ => {}
+        }
+        response
     }
 }
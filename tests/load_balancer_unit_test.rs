@@ -1,64 +1,344 @@
-#[cfg(test)]
-mod tests {
-    use std::sync::Arc;
-    use super::*;
-
-    // Note: This is a simplified test since we can't easily import the actual structs
-    // In a real implementation, these would be proper unit tests
-
-    #[test]
-    fn test_round_robin_logic() {
-        // Simulate the round-robin logic
-        let total_servers = 3;
-        let mut current = 0;
-
-        // Simulate 6 requests
-        for i in 0..6 {
-            let selected = current % total_servers;
-            println!("Request {} -> Server {}", i + 1, selected);
-            current += 1;
-        }
+use leyline_core::balancer::hash::{hash_bytes, request_hash};
+use leyline_core::balancer::{
+    LeastRequest, LoadBalancer, Maglev, P2cEwma, PickContext, Random, RingHash, RoundRobin, WeightedRoundRobin,
+};
+use leyline_core::config::CircuitBreakerConfig;
+use leyline_core::endpoint::Endpoint;
+use leyline_core::service::UpstreamService;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+fn upstreams(count: usize) -> Vec<String> {
+    (0..count).map(|i| format!("http://127.0.0.1:{}", 8080 + i)).collect()
+}
+
+fn endpoints(weights: &[u32]) -> Vec<Arc<Endpoint>> {
+    weights
+        .iter()
+        .enumerate()
+        .map(|(i, &weight)| Arc::new(Endpoint::new(format!("http://127.0.0.1:{}", 8080 + i), weight)))
+        .collect()
+}
+
+fn picks(load_balancer: &dyn LoadBalancer, endpoints: &[Arc<Endpoint>], count: usize) -> Vec<usize> {
+    (0..count)
+        .map(|_| load_balancer.pick(endpoints, &PickContext::default()).unwrap())
+        .collect()
+}
+
+#[test]
+fn test_round_robin_rotates() {
+    let endpoints = endpoints(&[1, 1, 1]);
+    let selections = picks(&RoundRobin::new(), &endpoints, 6);
+
+    assert_eq!(selections, vec![0, 1, 2, 0, 1, 2]);
+}
+
+#[test]
+fn test_single_server() {
+    let endpoints = endpoints(&[1]);
+    let selections = picks(&RoundRobin::new(), &endpoints, 3);
 
-        // Expected: 0, 1, 2, 0, 1, 2
-        assert_eq!(0 % 3, 0);
-        assert_eq!(1 % 3, 1);
-        assert_eq!(2 % 3, 2);
-        assert_eq!(3 % 3, 0);
-        assert_eq!(4 % 3, 1);
-        assert_eq!(5 % 3, 2);
+    assert_eq!(selections, vec![0, 0, 0]);
+}
+
+#[test]
+fn test_round_robin_is_even_across_threads() {
+    let load_balancer = Arc::new(RoundRobin::new());
+    let endpoints = Arc::new(endpoints(&[1, 1, 1, 1]));
+
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let load_balancer = load_balancer.clone();
+            let endpoints = endpoints.clone();
+            std::thread::spawn(move || picks(load_balancer.as_ref(), &endpoints, 100))
+        })
+        .collect();
+
+    let mut counts = HashMap::new();
+    for handle in handles {
+        for index in handle.join().unwrap() {
+            *counts.entry(index).or_insert(0) += 1;
+        }
     }
 
-    #[test]
-    fn test_two_servers_round_robin() {
-        let total_servers = 2;
-        let mut current = 0;
-        let mut selections = Vec::new();
-
-        // Simulate 6 requests
-        for _ in 0..6 {
-            let selected = current % total_servers;
-            selections.push(selected);
-            current += 1;
+    assert_eq!(counts.len(), 4);
+    assert!(counts.values().all(|&count| count == 200));
+}
+
+#[test]
+fn test_each_request_starts_at_next_server() {
+    let service = UpstreamService::with_config("/py", upstreams(2), 10, 1);
+
+    let starts: Vec<usize> = (0..4).map(|_| service.pick(&[]).unwrap()).collect();
+
+    assert_eq!(starts, vec![0, 1, 0, 1]);
+}
+
+#[test]
+fn test_retries_continue_from_chosen_server() {
+    let service = UpstreamService::with_config("/py", upstreams(3), 10, 3);
+
+    let attempts = |service: &UpstreamService| {
+        let mut tried = Vec::new();
+        while let Some(index) = service.pick(&tried) {
+            tried.push(index);
         }
+        tried
+    };
 
-        // Should alternate between 0 and 1
-        assert_eq!(selections, vec![0, 1, 0, 1, 0, 1]);
+    assert_eq!(attempts(&service), vec![0, 1, 2]);
+    assert_eq!(attempts(&service), vec![1, 2, 0]);
+    assert_eq!(attempts(&service), vec![2, 0, 1]);
+}
+
+#[test]
+fn test_max_retries_capped_by_servers() {
+    let service = UpstreamService::with_config("/py", upstreams(2), 10, 5);
+
+    assert_eq!(service.max_retries, 2);
+}
+
+#[test]
+fn test_smooth_weighted_round_robin() {
+    let endpoints = endpoints(&[5, 1, 1]);
+    let load_balancer = WeightedRoundRobin::new(endpoints.len());
+
+    // nginx's example sequence for weights 5, 1, 1
+    assert_eq!(picks(&load_balancer, &endpoints, 7), vec![0, 0, 1, 0, 2, 0, 0]);
+}
+
+#[test]
+fn test_weighted_round_robin_skips_tried() {
+    let endpoints = endpoints(&[3, 1]);
+    let load_balancer = WeightedRoundRobin::new(endpoints.len());
+
+    let retry = load_balancer.pick(&endpoints, &PickContext { tried: &[0], ..Default::default() });
+    assert_eq!(retry, Some(1));
+    assert_eq!(load_balancer.pick(&endpoints, &PickContext { tried: &[0, 1], ..Default::default() }), None);
+}
+
+#[test]
+fn test_least_request_prefers_idle_endpoint() {
+    let endpoints = endpoints(&[1, 1, 1]);
+    let _busy = [endpoints[0].start_request(), endpoints[0].start_request(), endpoints[2].start_request()];
+
+    assert_eq!(endpoints[0].in_flight(), 2);
+    assert_eq!(picks(&LeastRequest, &endpoints, 5), vec![1; 5]);
+    assert_eq!(LeastRequest.pick(&endpoints, &PickContext { tried: &[1], ..Default::default() }), Some(2));
+}
+
+#[test]
+fn test_in_flight_released_on_drop() {
+    let endpoints = endpoints(&[1]);
+    {
+        let _guard = endpoints[0].start_request();
+        assert_eq!(endpoints[0].in_flight(), 1);
     }
+    assert_eq!(endpoints[0].in_flight(), 0);
+}
 
-    #[test]
-    fn test_single_server() {
-        let total_servers = 1;
-        let mut current = 0;
-        let mut selections = Vec::new();
-
-        // Simulate 3 requests
-        for _ in 0..3 {
-            let selected = current % total_servers;
-            selections.push(selected);
-            current += 1;
-        }
+#[test]
+fn test_latency_recorded_apart_from_in_flight() {
+    let endpoints = endpoints(&[1]);
+    let guard = endpoints[0].start_request();
+    let timer = endpoints[0].start_timer();
+    std::thread::sleep(Duration::from_millis(20));
+    drop(timer);
+    let latency = endpoints[0].ewma_latency();
+    assert!(latency >= Duration::from_millis(20), "{:?}", latency);
+    assert_eq!(endpoints[0].in_flight(), 1);
+
+    // A long body doesn't add to the latency
+    std::thread::sleep(Duration::from_millis(20));
+    drop(guard);
+    assert_eq!(endpoints[0].in_flight(), 0);
+    assert_eq!(endpoints[0].ewma_latency(), latency);
+}
+
+#[test]
+fn test_random_covers_all_eligible() {
+    let endpoints = endpoints(&[1, 1, 1]);
+    let selections = picks(&Random, &endpoints, 300);
+
+    assert!((0..3).all(|index| selections.contains(&index)));
+    assert_eq!(Random.pick(&endpoints, &PickContext { tried: &[0, 2], ..Default::default() }), Some(1));
+}
+
+#[test]
+fn test_p2c_prefers_lower_latency() {
+    let endpoints = endpoints(&[1, 1]);
+    endpoints[0].record_latency(Duration::from_millis(500));
+    endpoints[1].record_latency(Duration::from_millis(5));
 
-        // Should always select server 0
-        assert_eq!(selections, vec![0, 0, 0]);
+    assert_eq!(picks(&P2cEwma, &endpoints, 10), vec![1; 10]);
+    assert_eq!(P2cEwma.pick(&endpoints, &PickContext { tried: &[1], ..Default::default() }), Some(0));
+}
+
+#[test]
+fn test_ewma_latency_moves_towards_new_samples() {
+    let endpoints = endpoints(&[1]);
+    endpoints[0].record_latency(Duration::from_millis(100));
+    assert_eq!(endpoints[0].ewma_latency(), Duration::from_millis(100));
+
+    std::thread::sleep(Duration::from_millis(50));
+    endpoints[0].record_latency(Duration::from_millis(1000));
+    let ewma = endpoints[0].ewma_latency();
+    assert!(ewma > Duration::from_millis(100) && ewma < Duration::from_millis(1000), "{:?}", ewma);
+}
+
+fn hashed_picks(load_balancer: &dyn LoadBalancer, endpoints: &[Arc<Endpoint>], keys: usize) -> Vec<String> {
+    (0..keys)
+        .map(|key| {
+            let hash = Some(hash_bytes(format!("user-{}", key).as_bytes()));
+            let index = load_balancer.pick(endpoints, &PickContext { tried: &[], hash, ..Default::default() }).unwrap();
+            endpoints[index].url.clone()
+        })
+        .collect()
+}
+
+fn moved_fraction(before: &[String], after: &[String]) -> f64 {
+    let moved = before.iter().zip(after).filter(|(a, b)| a != b).count();
+    moved as f64 / before.len() as f64
+}
+
+#[test]
+fn test_ring_hash_is_sticky_per_key() {
+    let endpoints = endpoints(&[1, 1, 1]);
+    let load_balancer = RingHash::new(&endpoints);
+
+    assert_eq!(hashed_picks(&load_balancer, &endpoints, 100), hashed_picks(&load_balancer, &endpoints, 100));
+}
+
+#[test]
+fn test_ring_hash_minimal_remapping() {
+    let three = endpoints(&[1, 1, 1]);
+    let four = endpoints(&[1, 1, 1, 1]);
+
+    let before = hashed_picks(&RingHash::new(&three), &three, 2000);
+    let after = hashed_picks(&RingHash::new(&four), &four, 2000);
+
+    // Ideally a quarter of the keys move to the new endpoint and nothing else changes
+    let moved = moved_fraction(&before, &after);
+    assert!(moved > 0.1 && moved < 0.35, "moved {}", moved);
+    assert!(before.iter().zip(&after).all(|(a, b)| a == b || b.ends_with(":8083")));
+}
+
+#[test]
+fn test_ring_hash_retry_skips_tried() {
+    let endpoints = endpoints(&[1, 1]);
+    let load_balancer = RingHash::new(&endpoints);
+    let hash = Some(hash_bytes(b"user-1"));
+
+    let first = load_balancer.pick(&endpoints, &PickContext { tried: &[], hash, ..Default::default() }).unwrap();
+    let retry = load_balancer.pick(&endpoints, &PickContext { tried: &[first], hash, ..Default::default() }).unwrap();
+    assert_ne!(first, retry);
+    assert_eq!(load_balancer.pick(&endpoints, &PickContext { tried: &[0, 1], hash, ..Default::default() }), None);
+}
+
+#[test]
+fn test_ring_hash_caps_huge_weights() {
+    let endpoints = endpoints(&[u32::MAX, u32::MAX / 2]);
+    let load_balancer = RingHash::new(&endpoints);
+
+    let picks = hashed_picks(&load_balancer, &endpoints, 3000);
+    let heavy = picks.iter().filter(|url| url.ends_with(":8080")).count();
+    assert!(heavy > 1700 && heavy < 2300, "heavy endpoint got {}", heavy);
+}
+
+#[test]
+fn test_maglev_is_even_and_sticky() {
+    let endpoints = endpoints(&[1, 1, 1]);
+    let load_balancer = Maglev::new(&endpoints);
+
+    let picks = hashed_picks(&load_balancer, &endpoints, 3000);
+    assert_eq!(picks, hashed_picks(&load_balancer, &endpoints, 3000));
+    for endpoint in endpoints.iter() {
+        let share = picks.iter().filter(|url| **url == endpoint.url).count() as f64 / 3000.0;
+        assert!(share > 0.28 && share < 0.39, "{} got {}", endpoint.url, share);
+    }
+}
+
+#[test]
+fn test_maglev_minimal_remapping() {
+    let four = endpoints(&[1, 1, 1, 1]);
+    let three: Vec<_> = four[..3].to_vec();
+
+    let before = hashed_picks(&Maglev::new(&four), &four, 2000);
+    let after = hashed_picks(&Maglev::new(&three), &three, 2000);
+
+    // Removing one of four endpoints should move roughly its quarter of the keys
+    let moved = moved_fraction(&before, &after);
+    assert!(moved > 0.15 && moved < 0.4, "moved {}", moved);
+}
+
+#[test]
+fn test_request_hash_sources() {
+    use axum::http::{HeaderMap, Uri};
+    use leyline_core::config::HashKey;
+
+    let mut headers = HeaderMap::new();
+    headers.insert("x-user-id", "42".parse().unwrap());
+    headers.insert("cookie", "theme=dark; session=abc".parse().unwrap());
+    let uri: Uri = "/py/items?page=2&user=7".parse().unwrap();
+    let ip = "10.0.0.1".parse().ok();
+
+    let hash = |key: HashKey| request_hash(&key, &headers, &uri, ip);
+    assert_eq!(hash(HashKey::Header("x-user-id".into())), Some(hash_bytes(b"42")));
+    assert_eq!(hash(HashKey::Cookie("session".into())), Some(hash_bytes(b"abc")));
+    assert_eq!(hash(HashKey::Query("user".into())), Some(hash_bytes(b"7")));
+    assert_eq!(hash(HashKey::ClientIp), Some(hash_bytes(b"10.0.0.1")));
+    assert_eq!(hash(HashKey::Header("x-missing".into())), None);
+}
+
+#[test]
+fn test_unhealthy_endpoints_are_skipped() {
+    let service = UpstreamService::with_config("/py", upstreams(4), 10, 3);
+    service.endpoints[1].set_healthy(false);
+
+    // The remaining servers share the load evenly
+    let mut counts = [0; 4];
+    for _ in 0..300 {
+        counts[service.pick(&[]).unwrap()] += 1;
+    }
+    assert_eq!(counts, [100, 0, 100, 100]);
+
+    let ring = RingHash::new(&service.endpoints);
+    let picks = hashed_picks(&ring, &service.endpoints, 200);
+    assert!(picks.iter().all(|url| !url.ends_with(":8081")));
+}
+
+#[test]
+fn test_open_circuit_endpoints_are_skipped() {
+    let circuit = CircuitBreakerConfig {
+        failure_threshold: 2,
+        open_seconds: 30,
+        half_open_max_requests: 1,
+        success_threshold: 1,
+    };
+    let endpoints: Vec<Arc<Endpoint>> = upstreams(2)
+        .into_iter()
+        .map(|url| Arc::new(Endpoint::new(url, 1).with_circuit_breaker(&circuit)))
+        .collect();
+    for _ in 0..2 {
+        endpoints[0].circuit_breaker().unwrap().try_acquire().unwrap().record(false);
     }
+
+    assert!(!endpoints[0].is_available());
+    let round_robin = RoundRobin::new();
+    assert_eq!(picks(&round_robin, &endpoints, 3), vec![1, 1, 1]);
+}
+
+#[test]
+fn test_all_unhealthy_falls_back_to_panic_routing() {
+    let service = UpstreamService::with_config("/py", upstreams(2), 10, 2);
+    for endpoint in &service.endpoints {
+        endpoint.set_healthy(false);
+    }
+
+    let first = service.pick(&[]).unwrap();
+    let second = service.pick(&[first]).unwrap();
+    assert_ne!(first, second);
+    assert_eq!(service.pick(&[first, second]), None);
 }